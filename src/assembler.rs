@@ -0,0 +1,995 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use cpu::{Rom, MEMORY_SIZE, PROGRAM_ADDRESS, XO_MEMORY_SIZE};
+use instruction::Instruction;
+use quirks::Platform;
+
+/// How deeply includes may nest. This also
+/// catches files that include themselves.
+const INCLUDE_DEPTH: usize = 16;
+
+/// An assembled program.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Assembly {
+    // The program, to be loaded at PROGRAM_ADDRESS.
+    pub rom: Rom,
+    // The value of every label and constant.
+    pub symbols: BTreeMap<String, i64>,
+    // The first platform that runs every instruction,
+    // with the memory to hold the program.
+    pub platform: Platform
+}
+
+/// Where something is in the source. Lines and columns
+/// count from 1, and are 0 for the file as a whole.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Position {
+    // The file, or None for source given as a string.
+    pub file: Option<PathBuf>,
+    pub line: usize,
+    pub column: usize
+}
+
+/// A problem with the source, and where it is.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AssemblyError {
+    pub position: Position,
+    pub message: String
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Token {
+    Ident(String),
+    Number(i64),
+    Str(Vec<u8>),
+    // Punctuation and operators, including << and >>.
+    Symbol(&'static str)
+}
+
+/// A token and the column it starts at.
+#[derive(Clone, Debug)]
+struct Spanned {
+    token: Token,
+    column: usize
+}
+
+/// An expression. Each part has the column it starts at,
+/// or for binary operators, the column of the operator.
+#[derive(Clone, Debug)]
+enum Expr {
+    Number(i64, usize),
+    Name(String, usize),
+    Negate(Box<Expr>, usize),
+    Not(Box<Expr>, usize),
+    Binary(&'static str, Box<Expr>, Box<Expr>, usize)
+}
+
+/// The fixed operands, which can't be used as symbol names.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Key {
+    I,
+    // [I]
+    AtI,
+    DT,
+    ST,
+    K,
+    F,
+    HF,
+    B,
+    R,
+    Pitch
+}
+
+#[derive(Clone, Debug)]
+enum Operand {
+    Register(u8),
+    Keyword(Key),
+    // LONG expr, for F000 NNNN.
+    Long(Expr),
+    Value(Expr)
+}
+
+#[derive(Clone, Debug)]
+enum Item {
+    Value(Expr),
+    Str(Vec<u8>)
+}
+
+#[derive(Clone, Debug)]
+enum Kind {
+    Label(String),
+    Constant(String, Expr),
+    Org(Expr),
+    Bytes(Vec<Item>),
+    Words(Vec<Expr>),
+    Instruction(String, Vec<Operand>)
+}
+
+/// A statement, and the position of its first token.
+#[derive(Clone, Debug)]
+struct Statement {
+    kind: Kind,
+    position: Position
+}
+
+/// A symbol, which is either an address or
+/// an expression that's evaluated when it's used.
+#[derive(Clone, Debug)]
+enum Symbol {
+    Address(i64),
+    Constant(Expr, Position)
+}
+
+impl fmt::Display for AssemblyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let Position { ref file, line, column } = self.position;
+
+        if let Some(ref file) = *file {
+            write!(f, "{}:", file.display())?
+        }
+
+        if line > 0 {
+            write!(f, "{}:{}:", line, column)?
+        }
+
+        write!(f, " {}", self.message)
+    }
+}
+
+impl Error for AssemblyError {}
+
+impl Expr {
+    /// The column the whole expression starts at.
+    fn column(&self) -> usize {
+        match *self {
+            Expr::Number(_, column) | Expr::Name(_, column) |
+            Expr::Negate(_, column) | Expr::Not(_, column) => column,
+            Expr::Binary(_, ref left, _, _) => left.column()
+        }
+    }
+}
+
+impl Position {
+    fn at(&self, column: usize) -> Position {
+        Position { column, ..self.clone() }
+    }
+
+    fn error<S: Into<String>>(&self, message: S) -> AssemblyError {
+        AssemblyError { position: self.clone(), message: message.into() }
+    }
+}
+
+/// Split a line into tokens, up to any comment.
+fn tokenize(line: &str, position: &Position) -> Result<Vec<Spanned>, AssemblyError> {
+    let chars: Vec<char> = line.chars().collect();
+    let mut tokens = vec![];
+    let mut i = 0;
+
+    // Reads a run of digits in a base, which must not be empty.
+    let digits = |i: &mut usize, radix: u32, column: usize| -> Result<i64, AssemblyError> {
+        let start = *i;
+
+        while *i < chars.len() && (chars[*i].is_digit(radix) || chars[*i] == '_') {
+            *i += 1
+        }
+
+        let text: String = chars[start .. *i].iter().filter(|&&c| c != '_').collect();
+
+        i64::from_str_radix(&text, radix)
+            .map_err(|_| position.at(column).error("bad number"))
+    };
+
+    while i < chars.len() {
+        let c = chars[i];
+        let column = i + 1;
+
+        let token = match c {
+            ';' => break,
+            _ if c.is_whitespace() => { i += 1; continue },
+
+            _ if c.is_alphabetic() || c == '_' || c == '.' => {
+                let start = i;
+
+                while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_' || chars[i] == '.') {
+                    i += 1
+                }
+
+                Token::Ident(chars[start .. i].iter().collect())
+            },
+
+            '0' if i + 1 < chars.len() && (chars[i + 1] == 'x' || chars[i + 1] == 'X') => {
+                i += 2;
+                Token::Number(digits(&mut i, 16, column)?)
+            },
+
+            '0' if i + 1 < chars.len() && (chars[i + 1] == 'b' || chars[i + 1] == 'B') => {
+                i += 2;
+                Token::Number(digits(&mut i, 2, column)?)
+            },
+
+            _ if c.is_ascii_digit() => Token::Number(digits(&mut i, 10, column)?),
+
+            '#' | '$' => {
+                i += 1;
+                Token::Number(digits(&mut i, 16, column)?)
+            },
+
+            '%' => {
+                i += 1;
+                Token::Number(digits(&mut i, 2, column)?)
+            },
+
+            '"' | '\'' => {
+                let mut text = vec![];
+                i += 1;
+
+                loop {
+                    let c = match chars.get(i) {
+                        Some(&c) => c,
+                        None => return Err(position.at(column).error("unterminated string"))
+                    };
+
+                    i += 1;
+
+                    if c == chars[column - 1] { break }
+
+                    let c = if c == '\\' {
+                        i += 1;
+
+                        match chars.get(i - 1) {
+                            Some('n') => '\n',
+                            Some('0') => '\0',
+                            Some(&c) => c,
+                            None => return Err(position.at(column).error("unterminated string"))
+                        }
+                    } else {
+                        c
+                    };
+
+                    let mut buffer = [0; 4];
+                    text.extend_from_slice(c.encode_utf8(&mut buffer).as_bytes())
+                }
+
+                // Single quotes are a character's value.
+                if c == '\'' {
+                    match text.len() {
+                        1 => Token::Number(text[0] as i64),
+                        _ => return Err(position.at(column).error("a character must be one byte"))
+                    }
+                } else {
+                    Token::Str(text)
+                }
+            },
+
+            _ => {
+                let next = chars.get(i + 1).cloned();
+
+                let symbol = match (c, next) {
+                    ('<', Some('<')) => "<<",
+                    ('>', Some('>')) => ">>",
+                    (',', _) => ",", (':', _) => ":", ('=', _) => "=",
+                    ('[', _) => "[", (']', _) => "]", ('(', _) => "(", (')', _) => ")",
+                    ('+', _) => "+", ('-', _) => "-", ('*', _) => "*", ('/', _) => "/",
+                    ('&', _) => "&", ('|', _) => "|", ('^', _) => "^", ('~', _) => "~",
+                    _ => return Err(position.at(column).error(format!("unexpected {:?}", c)))
+                };
+
+                i += symbol.len();
+                Token::Symbol(symbol)
+            }
+        };
+
+        tokens.push(Spanned { token, column })
+    }
+
+    Ok(tokens)
+}
+
+/// Parses expressions from a line's tokens, by precedence
+/// climbing. From loosest to tightest: |, ^, &, << and >>,
+/// + and -, then * and /. - and ~ are unary.
+struct Parser<'a> {
+    tokens: &'a [Spanned],
+    next: usize,
+    position: &'a Position,
+    // Column just past the last token, for errors at the end.
+    end: usize
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&'a Token> {
+        self.tokens.get(self.next).map(|spanned| &spanned.token)
+    }
+
+    fn column(&self) -> usize {
+        self.tokens.get(self.next).map(|spanned| spanned.column).unwrap_or(self.end)
+    }
+
+    fn error<S: Into<String>>(&self, message: S) -> AssemblyError {
+        self.position.at(self.column()).error(message)
+    }
+
+    fn expression(&mut self) -> Result<Expr, AssemblyError> {
+        self.binary(0)
+    }
+
+    fn binary(&mut self, level: usize) -> Result<Expr, AssemblyError> {
+        const LEVELS: [&[&str]; 5] = [&["|"], &["^"], &["&"], &["<<", ">>"], &["+", "-"]];
+
+        if level == LEVELS.len() {
+            return self.product()
+        }
+
+        let mut left = self.binary(level + 1)?;
+
+        while let Some(&Token::Symbol(op)) = self.peek() {
+            if !LEVELS[level].contains(&op) { break }
+
+            let column = self.column();
+            self.next += 1;
+            let right = self.binary(level + 1)?;
+            left = Expr::Binary(op, Box::new(left), Box::new(right), column)
+        }
+
+        Ok(left)
+    }
+
+    fn product(&mut self) -> Result<Expr, AssemblyError> {
+        let mut left = self.unary()?;
+
+        while let Some(&Token::Symbol(op)) = self.peek() {
+            if op != "*" && op != "/" { break }
+
+            let column = self.column();
+            self.next += 1;
+            let right = self.unary()?;
+            left = Expr::Binary(op, Box::new(left), Box::new(right), column)
+        }
+
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Expr, AssemblyError> {
+        let column = self.column();
+
+        let expr = match self.peek() {
+            Some(&Token::Number(value)) => Expr::Number(value, column),
+            Some(Token::Ident(name)) => Expr::Name(name.clone(), column),
+
+            Some(&Token::Symbol("-")) => {
+                self.next += 1;
+                return Ok(Expr::Negate(Box::new(self.unary()?), column))
+            },
+
+            Some(&Token::Symbol("~")) => {
+                self.next += 1;
+                return Ok(Expr::Not(Box::new(self.unary()?), column))
+            },
+
+            Some(&Token::Symbol("(")) => {
+                self.next += 1;
+                let expr = self.expression()?;
+
+                if self.peek() != Some(&Token::Symbol(")")) {
+                    return Err(self.error("expected )"))
+                }
+
+                expr
+            },
+
+            _ => return Err(self.error("expected a value"))
+        };
+
+        self.next += 1;
+        Ok(expr)
+    }
+
+    /// Parse a whole operand, up to a comma or the end.
+    fn operand(&mut self) -> Result<Operand, AssemblyError> {
+        let rest = &self.tokens[self.next ..];
+        let single = rest.len() == 1 || rest.get(1).map(|t| &t.token) == Some(&Token::Symbol(","));
+
+        if let Some(Token::Ident(name)) = self.peek() {
+            let upper = name.to_uppercase();
+
+            if single {
+                let register = if upper.len() == 2 && upper.starts_with('V') {
+                    u8::from_str_radix(&upper[1 ..], 16).ok()
+                } else {
+                    None
+                };
+
+                let key = match upper.as_str() {
+                    "I" => Some(Key::I),
+                    "DT" => Some(Key::DT),
+                    "ST" => Some(Key::ST),
+                    "K" => Some(Key::K),
+                    "F" => Some(Key::F),
+                    "HF" => Some(Key::HF),
+                    "B" => Some(Key::B),
+                    "R" => Some(Key::R),
+                    "PITCH" => Some(Key::Pitch),
+                    _ => None
+                };
+
+                if let Some(x) = register {
+                    self.next += 1;
+                    return Ok(Operand::Register(x))
+                }
+
+                if let Some(key) = key {
+                    self.next += 1;
+                    return Ok(Operand::Keyword(key))
+                }
+            }
+
+            if upper == "LONG" {
+                self.next += 1;
+                return Ok(Operand::Long(self.expression()?))
+            }
+        }
+
+        let indirect = [Token::Symbol("["), Token::Ident("I".to_string()), Token::Symbol("]")];
+
+        if rest.len() >= 3 && rest[.. 3].iter().zip(&indirect).all(|(a, b)| {
+            match (&a.token, b) {
+                (Token::Ident(name), &Token::Ident(_)) => name.eq_ignore_ascii_case("I"),
+                (a, b) => a == b
+            }
+        }) {
+            self.next += 3;
+            return Ok(Operand::Keyword(Key::AtI))
+        }
+
+        Ok(Operand::Value(self.expression()?))
+    }
+
+    /// Parse items separated by commas, up to the end.
+    fn list<T, F>(&mut self, mut item: F) -> Result<Vec<T>, AssemblyError>
+        where F: FnMut(&mut Parser<'a>) -> Result<T, AssemblyError> {
+        let mut items = vec![];
+
+        if self.peek().is_none() {
+            return Ok(items)
+        }
+
+        loop {
+            items.push(item(self)?);
+
+            match self.peek() {
+                None => return Ok(items),
+                Some(&Token::Symbol(",")) => self.next += 1,
+                Some(_) => return Err(self.error("expected , or the end of the line"))
+            }
+        }
+    }
+
+    fn finish(&self) -> Result<(), AssemblyError> {
+        match self.peek() {
+            None => Ok(()),
+            Some(_) => Err(self.error("expected the end of the line"))
+        }
+    }
+}
+
+/// Collects the statements of a file and its includes.
+struct Reader {
+    statements: Vec<Statement>
+}
+
+impl Reader {
+    fn read(&mut self, source: &str, file: Option<&Path>, depth: usize) -> Result<(), AssemblyError> {
+        for (number, line) in source.lines().enumerate() {
+            let position = Position {
+                file: file.map(Path::to_path_buf),
+                line: number + 1,
+                column: 1
+            };
+
+            let tokens = tokenize(line, &position)?;
+            let end = line.chars().count() + 1;
+            let mut parser = Parser { tokens: &tokens, next: 0, position: &position, end };
+
+            self.line(&mut parser, file, depth)?
+        }
+
+        Ok(())
+    }
+
+    fn push(&mut self, kind: Kind, parser: &Parser, column: usize) {
+        self.statements.push(Statement { kind, position: parser.position.at(column) })
+    }
+
+    fn line(&mut self, parser: &mut Parser, file: Option<&Path>, depth: usize) -> Result<(), AssemblyError> {
+        let tokens = parser.tokens;
+
+        let name = match tokens.first() {
+            Some(&Spanned { token: Token::Ident(ref name), column }) => (name.clone(), column),
+            Some(_) => return Err(parser.error("expected a label, directive or instruction")),
+            None => return Ok(())
+        };
+
+        let second = tokens.get(1).map(|spanned| &spanned.token);
+
+        // A label, which may be followed by a statement.
+        if second == Some(&Token::Symbol(":")) {
+            self.push(Kind::Label(name.0), parser, name.1);
+            parser.next = 2;
+
+            if parser.peek().is_none() {
+                return Ok(())
+            }
+
+            let rest = Parser { tokens: &tokens[2 ..], next: 0, ..*parser };
+            return self.line(&mut { rest }, file, depth)
+        }
+
+        // A constant, as NAME EQU value or NAME = value.
+        let constant = match second {
+            Some(&Token::Symbol("=")) => true,
+            Some(Token::Ident(word)) => word.eq_ignore_ascii_case("equ"),
+            _ => false
+        };
+
+        if constant {
+            parser.next = 2;
+            let value = parser.expression()?;
+            parser.finish()?;
+            self.push(Kind::Constant(name.0, value), parser, name.1);
+            return Ok(())
+        }
+
+        parser.next = 1;
+
+        let kind = match name.0.to_lowercase().as_str() {
+            "db" | "byte" => Kind::Bytes(parser.list(|parser| {
+                if let Some(Token::Str(text)) = parser.peek() {
+                    parser.next += 1;
+                    Ok(Item::Str(text.clone()))
+                } else {
+                    parser.expression().map(Item::Value)
+                }
+            })?),
+
+            "dw" | "word" => Kind::Words(parser.list(Parser::expression)?),
+
+            "org" => Kind::Org(parser.expression()?),
+
+            "include" => {
+                let path = match parser.peek() {
+                    Some(Token::Str(path)) => String::from_utf8_lossy(path).into_owned(),
+                    _ => return Err(parser.error("include needs a file name in quotes"))
+                };
+
+                parser.next += 1;
+                parser.finish()?;
+
+                if depth >= INCLUDE_DEPTH {
+                    return Err(parser.position.at(name.1).error("includes are nested too deeply"))
+                }
+
+                // Paths are relative to the including file.
+                let path = match file.and_then(Path::parent) {
+                    Some(directory) => directory.join(path),
+                    None => PathBuf::from(path)
+                };
+
+                let mut source = String::new();
+                File::open(&path).and_then(|mut file| file.read_to_string(&mut source))
+                    .map_err(|error| parser.position.at(name.1).error(
+                        format!("couldn't include {}: {}", path.display(), error)))?;
+
+                return self.read(&source, Some(&path), depth + 1)
+            },
+
+            _ => Kind::Instruction(name.0.to_uppercase(), parser.list(Parser::operand)?)
+        };
+
+        parser.finish()?;
+        self.push(kind, parser, name.1);
+        Ok(())
+    }
+}
+
+/// Assigns addresses, then evaluates everything.
+struct Assembler {
+    symbols: BTreeMap<String, Symbol>
+}
+
+impl Assembler {
+    fn define(&mut self, name: &str, symbol: Symbol, position: &Position) -> Result<(), AssemblyError> {
+        if self.symbols.contains_key(name) {
+            return Err(position.error(format!("{} is already defined", name)))
+        }
+
+        self.symbols.insert(name.to_string(), symbol);
+        Ok(())
+    }
+
+    /// Evaluate an expression. Depth counts how many constants
+    /// deep this is, to catch constants defined by themselves.
+    fn evaluate(&self, expr: &Expr, position: &Position, depth: usize) -> Result<i64, AssemblyError> {
+        match *expr {
+            Expr::Number(value, _) => Ok(value),
+
+            Expr::Name(ref name, column) => match self.symbols.get(name) {
+                Some(&Symbol::Address(address)) => Ok(address),
+
+                Some(Symbol::Constant(expr, defined)) => {
+                    if depth > self.symbols.len() {
+                        return Err(position.at(column).error(format!("{} is defined in terms of itself", name)))
+                    }
+
+                    self.evaluate(expr, defined, depth + 1)
+                },
+
+                None => Err(position.at(column).error(format!("{} isn't defined", name)))
+            },
+
+            Expr::Negate(ref expr, _) => Ok(self.evaluate(expr, position, depth)?.wrapping_neg()),
+            Expr::Not(ref expr, _) => Ok(!self.evaluate(expr, position, depth)?),
+
+            Expr::Binary(op, ref left, ref right, column) => {
+                let left = self.evaluate(left, position, depth)?;
+                let right = self.evaluate(right, position, depth)?;
+
+                let value = match op {
+                    "+" => left.checked_add(right),
+                    "-" => left.checked_sub(right),
+                    "*" => left.checked_mul(right),
+                    "/" => left.checked_div(right),
+                    "&" => Some(left & right),
+                    "|" => Some(left | right),
+                    "^" => Some(left ^ right),
+                    "<<" if (0 .. 64).contains(&right) => left.checked_shl(right as u32),
+                    ">>" if (0 .. 64).contains(&right) => left.checked_shr(right as u32),
+                    _ => None
+                };
+
+                value.ok_or_else(|| position.at(column).error(format!("can't evaluate {} here", op)))
+            }
+        }
+    }
+
+    /// Evaluate an expression that must be in a range.
+    fn value(&self, expr: &Expr, position: &Position, min: i64, max: i64, what: &str)
+        -> Result<i64, AssemblyError> {
+        let value = self.evaluate(expr, position, 0)?;
+
+        if value < min || value > max {
+            return Err(position.at(expr.column()).error(format!("{} doesn't fit in {}", value, what)))
+        }
+
+        Ok(value)
+    }
+
+    /// Encode an instruction, with the address following
+    /// it for F000 NNNN. Negative bytes are two's complement.
+    fn encode(&self, mnemonic: &str, operands: &[Operand], position: &Position)
+        -> Result<(Instruction, Option<u16>), AssemblyError> {
+        use self::Operand::*;
+        use instruction::Instruction::*;
+
+        let address = |expr| self.value(expr, position, 0, 0xFFF, "an address").map(|v| v as u16);
+        let byte = |expr| self.value(expr, position, -0x80, 0xFF, "a byte").map(|v| v as u8);
+        let nibble = |expr| self.value(expr, position, 0, 0xF, "a nibble").map(|v| v as u8);
+
+        let instruction = match (mnemonic, operands) {
+            ("CLS", []) => Clear,
+            ("RET", []) => Return,
+            ("SYS", [Value(a)]) => Sys { nnn: address(a)? },
+            ("SCD", [Value(n)]) => ScrollDown { n: nibble(n)? },
+            ("SCU", [Value(n)]) => ScrollUp { n: nibble(n)? },
+            ("SCR", []) => ScrollRight,
+            ("SCL", []) => ScrollLeft,
+            ("EXIT", []) => Exit,
+            ("LOW", []) => LowRes,
+            ("HIGH", []) => HighRes,
+            ("JP", [Value(a)]) => Jump { nnn: address(a)? },
+            ("JP", [Register(0), Value(a)]) => JumpOffset { nnn: address(a)? },
+            ("CALL", [Value(a)]) => Call { nnn: address(a)? },
+            ("SE", [Register(x), Value(b)]) => SkipEqImm { x: *x, nn: byte(b)? },
+            ("SE", [Register(x), Register(y)]) => SkipEq { x: *x, y: *y },
+            ("SNE", [Register(x), Value(b)]) => SkipNeImm { x: *x, nn: byte(b)? },
+            ("SNE", [Register(x), Register(y)]) => SkipNe { x: *x, y: *y },
+            ("SAVE", [Register(x), Register(y)]) => SaveRange { x: *x, y: *y },
+            ("LOAD", [Register(x), Register(y)]) => LoadRange { x: *x, y: *y },
+            ("LD", [Register(x), Value(b)]) => LoadImm { x: *x, nn: byte(b)? },
+            ("LD", [Register(x), Register(y)]) => Load { x: *x, y: *y },
+            ("LD", [Keyword(Key::I), Value(a)]) => LoadIndex { nnn: address(a)? },
+
+            ("LD", [Keyword(Key::I), Long(a)]) => {
+                let long = self.value(a, position, 0, 0xFFFF, "a long address")?;
+                return Ok((LongIndex, Some(long as u16)))
+            },
+
+            ("LD", [Register(x), Keyword(Key::DT)]) => LoadDelay { x: *x },
+            ("LD", [Register(x), Keyword(Key::K)]) => WaitKey { x: *x },
+            ("LD", [Keyword(Key::DT), Register(x)]) => SetDelay { x: *x },
+            ("LD", [Keyword(Key::ST), Register(x)]) => SetSound { x: *x },
+            ("LD", [Keyword(Key::F), Register(x)]) => LoadGlyph { x: *x },
+            ("LD", [Keyword(Key::HF), Register(x)]) => LoadBigGlyph { x: *x },
+            ("LD", [Keyword(Key::Pitch), Register(x)]) => SetPitch { x: *x },
+            ("LD", [Keyword(Key::B), Register(x)]) => StoreBcd { x: *x },
+            ("LD", [Keyword(Key::AtI), Register(x)]) => Store { x: *x },
+            ("LD", [Register(x), Keyword(Key::AtI)]) => Restore { x: *x },
+            ("LD", [Keyword(Key::R), Register(x)]) => SaveFlags { x: *x },
+            ("LD", [Register(x), Keyword(Key::R)]) => LoadFlags { x: *x },
+            ("ADD", [Register(x), Value(b)]) => AddImm { x: *x, nn: byte(b)? },
+            ("ADD", [Register(x), Register(y)]) => Add { x: *x, y: *y },
+            ("ADD", [Keyword(Key::I), Register(x)]) => AddIndex { x: *x },
+            ("OR", [Register(x), Register(y)]) => Or { x: *x, y: *y },
+            ("AND", [Register(x), Register(y)]) => And { x: *x, y: *y },
+            ("XOR", [Register(x), Register(y)]) => Xor { x: *x, y: *y },
+            ("SUB", [Register(x), Register(y)]) => Sub { x: *x, y: *y },
+            ("SUBN", [Register(x), Register(y)]) => SubReverse { x: *x, y: *y },
+
+            // With one register, it's shifted in place
+            // whichever way the interpreter shifts.
+            ("SHR", [Register(x)]) => ShiftRight { x: *x, y: *x },
+            ("SHR", [Register(x), Register(y)]) => ShiftRight { x: *x, y: *y },
+            ("SHL", [Register(x)]) => ShiftLeft { x: *x, y: *x },
+            ("SHL", [Register(x), Register(y)]) => ShiftLeft { x: *x, y: *y },
+
+            ("RND", [Register(x), Value(b)]) => Random { x: *x, nn: byte(b)? },
+            ("DRW", [Register(x), Register(y), Value(n)]) => Draw { x: *x, y: *y, n: nibble(n)? },
+            ("SKP", [Register(x)]) => SkipKey { x: *x },
+            ("SKNP", [Register(x)]) => SkipNotKey { x: *x },
+            ("PLANE", [Value(n)]) => SelectPlanes { x: nibble(n)? },
+            ("AUDIO", []) => LoadPattern,
+
+            _ => {
+                const MNEMONICS: [&str; 29] = [
+                    "CLS", "RET", "SYS", "SCD", "SCU", "SCR", "SCL", "EXIT", "LOW", "HIGH",
+                    "JP", "CALL", "SE", "SNE", "SAVE", "LOAD", "LD", "ADD", "OR", "AND",
+                    "XOR", "SUB", "SUBN", "SHR", "SHL", "RND", "DRW", "SKP", "SKNP"
+                ];
+
+                let known = MNEMONICS.contains(&mnemonic) || mnemonic == "PLANE" || mnemonic == "AUDIO";
+
+                return Err(position.error(if known {
+                    format!("wrong operands for {}", mnemonic)
+                } else {
+                    format!("unknown instruction {}", mnemonic)
+                }))
+            }
+        };
+
+        Ok((instruction, None))
+    }
+
+    /// Bytes a statement takes up, without evaluating it.
+    fn size(kind: &Kind) -> usize {
+        match *kind {
+            Kind::Bytes(ref items) => items.iter().map(|item| match *item {
+                Item::Value(_) => 1,
+                Item::Str(ref text) => text.len()
+            }).sum(),
+
+            Kind::Words(ref words) => words.len() * 2,
+
+            Kind::Instruction(ref mnemonic, ref operands) => match (mnemonic.as_str(), operands.as_slice()) {
+                ("LD", [Operand::Keyword(Key::I), Operand::Long(_)]) => 4,
+                _ => 2
+            },
+
+            _ => 0
+        }
+    }
+
+    fn assemble(mut self, statements: &[Statement]) -> Result<Assembly, AssemblyError> {
+        // The first pass gives labels their addresses.
+        // Addresses given to org can only use what's
+        // defined before them.
+        let mut address = PROGRAM_ADDRESS as i64;
+        let mut origins = vec![];
+
+        for statement in statements {
+            let position = &statement.position;
+
+            match statement.kind {
+                Kind::Label(ref name) =>
+                    self.define(name, Symbol::Address(address), position)?,
+
+                Kind::Constant(ref name, ref expr) =>
+                    self.define(name, Symbol::Constant(expr.clone(), position.clone()), position)?,
+
+                Kind::Org(ref expr) => {
+                    let origin = self.evaluate(expr, position, 0)?;
+
+                    if origin < address {
+                        return Err(position.error(format!("org {:#X} is before {:#X}", origin, address)))
+                    }
+
+                    address = origin;
+                    origins.push(origin)
+                },
+
+                ref kind => address += Assembler::size(kind) as i64
+            }
+
+            if address > XO_MEMORY_SIZE as i64 {
+                return Err(position.error("the program runs past the end of memory"))
+            }
+        }
+
+        // The second pass writes everything out.
+        let mut rom = vec![];
+        let mut platform = Platform::Chip8;
+        let mut origins = origins.into_iter();
+
+        for statement in statements {
+            let position = &statement.position;
+
+            match statement.kind {
+                Kind::Org(_) => {
+                    let origin = origins.next().unwrap_or(0) as usize;
+                    rom.resize(origin - PROGRAM_ADDRESS, 0)
+                },
+
+                Kind::Bytes(ref items) => for item in items {
+                    match *item {
+                        Item::Value(ref expr) =>
+                            rom.push(self.value(expr, position, -0x80, 0xFF, "a byte")? as u8),
+                        Item::Str(ref text) =>
+                            rom.extend_from_slice(text)
+                    }
+                },
+
+                Kind::Words(ref words) => for expr in words {
+                    let word = self.value(expr, position, -0x8000, 0xFFFF, "a word")? as u16;
+                    rom.extend_from_slice(&word.to_be_bytes())
+                },
+
+                Kind::Instruction(ref mnemonic, ref operands) => {
+                    let (instruction, long) = self.encode(mnemonic, operands, position)?;
+                    platform = platform.max(instruction.platform());
+                    rom.extend_from_slice(&instruction.encode().to_be_bytes());
+
+                    if let Some(long) = long {
+                        rom.extend_from_slice(&long.to_be_bytes())
+                    }
+                },
+
+                _ => {}
+            }
+        }
+
+        let mut symbols = BTreeMap::new();
+
+        for (name, symbol) in &self.symbols {
+            let value = match *symbol {
+                Symbol::Address(address) => address,
+                Symbol::Constant(ref expr, ref position) => self.evaluate(expr, position, 0)?
+            };
+
+            symbols.insert(name.clone(), value);
+        }
+
+        if rom.len() > MEMORY_SIZE - PROGRAM_ADDRESS {
+            platform = Platform::XoChip
+        }
+
+        Ok(Assembly { rom, symbols, platform })
+    }
+}
+
+/// Assemble source given as a string. Includes are
+/// found relative to the current directory.
+pub fn assemble(source: &str) -> Result<Assembly, AssemblyError> {
+    let mut reader = Reader { statements: vec![] };
+    reader.read(source, None, 0)?;
+
+    Assembler { symbols: BTreeMap::new() }.assemble(&reader.statements)
+}
+
+/// Assemble a source file. Includes are found
+/// relative to the file that includes them.
+pub fn assemble_file<P: AsRef<Path>>(path: P) -> Result<Assembly, AssemblyError> {
+    let path = path.as_ref();
+    let mut source = String::new();
+
+    File::open(path).and_then(|mut file| file.read_to_string(&mut source))
+        .map_err(|error| AssemblyError {
+            position: Position { file: Some(path.to_path_buf()), line: 0, column: 0 },
+            message: error.to_string()
+        })?;
+
+    let mut reader = Reader { statements: vec![] };
+    reader.read(&source, Some(path), 0)?;
+
+    Assembler { symbols: BTreeMap::new() }.assemble(&reader.statements)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use disassembler::{disassemble_source, Syntax};
+
+    /// Make up ROMs from a fixed seed, so every run tests the same ones.
+    fn roms(count: usize) -> Vec<Vec<u8>> {
+        let mut state: u32 = 0x2545_F491;
+
+        (0 .. count).map(|_| {
+            let mut next = || {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state
+            };
+
+            let length = 1 + next() as usize % 64;
+            (0 .. length).map(|_| next() as u8).collect()
+        }).collect()
+    }
+
+    #[test]
+    fn assembles_labels_constants_and_data() {
+        let assembly = assemble("\
+count equ 3
+start:
+    LD V0, count    ; a comment
+    LD I, sprite
+    DRW V0, V1, 5
+loop:
+    JP loop
+sprite:
+    db #F0, %10010000, 'A'
+    dw start
+").unwrap();
+
+        assert_eq!(assembly.rom, [
+            0x60, 0x03, 0xA2, 0x08, 0xD0, 0x15, 0x12, 0x06,
+            0xF0, 0x90, 0x41, 0x02, 0x00
+        ]);
+
+        assert_eq!(assembly.symbols["loop"], 0x206);
+        assert_eq!(assembly.symbols["count"], 3);
+        assert_eq!(assembly.platform, Platform::Chip8);
+    }
+
+    #[test]
+    fn reports_line_and_column() {
+        let cases = [
+            ("cls\n  ld v0, 300\n", 2, 10, "300 doesn't fit in a byte"),
+            ("start:\n  jp nowhere\n", 2, 6, "nowhere isn't defined"),
+            ("  add v0, v1, v2\n", 1, 3, "wrong operands for ADD"),
+            ("cls\ncls\n    frob v1\n", 3, 5, "unknown instruction FROB"),
+            ("  ld v0, #1 +\n", 1, 14, "expected a value"),
+            ("x: cls\nx: cls\n", 2, 1, "x is already defined"),
+            ("org #300\norg #200\n", 2, 1, "org 0x200 is before 0x300"),
+            ("  db 1, \"ab\n", 1, 9, "unterminated string")
+        ];
+
+        for &(source, line, column, message) in &cases {
+            let error = assemble(source).unwrap_err();
+            assert_eq!((error.position.line, error.position.column), (line, column), "{:?}", source);
+            assert_eq!(error.message, message);
+            assert_eq!(error.to_string(), format!("{}:{}: {}", line, column, message));
+        }
+    }
+
+    #[test]
+    fn reports_missing_files_without_a_line() {
+        let error = assemble_file("/nonexistent/game.s").unwrap_err();
+        assert_eq!(error.position.line, 0);
+        assert!(error.to_string().starts_with("/nonexistent/game.s: "));
+    }
+
+    #[test]
+    fn disassembly_assembles_to_the_same_bytes() {
+        let mut roms = roms(300);
+        roms.push(vec![0x00, 0xE0, 0xA2, 0x0A, 0x22, 0x08, 0x12, 0x06, 0x00, 0xEE, 0x3C, 0x42, 0x81]);
+        roms.push(vec![0xF0, 0x00, 0x02, 0x08, 0xF0, 0x02, 0x12, 0x06, 0xFF, 0x00]);
+
+        for rom in roms {
+            let source = disassemble_source(&rom, Syntax::Cowgod);
+            let assembly = assemble(&source).unwrap_or_else(|e| panic!("{}\n{}", e, source));
+            assert_eq!(assembly.rom, rom, "\n{}", source);
+        }
+    }
+}
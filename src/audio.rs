@@ -0,0 +1,271 @@
+use std::fs::File;
+use std::io::{BufWriter, Result as IOResult, Write};
+use std::path::Path;
+use cpu::{Chip8, DEFAULT_PITCH, FRAME_RATE};
+use quirks::Platform;
+
+/// Rate that frontends usually play samples at.
+pub const SAMPLE_RATE: u32 = 44100;
+
+/// Pitch of the square wave played when
+/// there's no XO-CHIP pattern, in Hz.
+pub const TONE: f64 = 440.0;
+
+/// Amplitude of every sample, to keep the
+/// square edges from being too harsh.
+pub const VOLUME: f32 = 0.25;
+
+/// The audio state of the interpreter at a moment,
+/// which is all a Synth needs to make samples.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Voice {
+    // Whether the sound timer is running.
+    pub playing: bool,
+    // The XO-CHIP pattern to play, or
+    // None for the plain square wave.
+    pub pattern: Option<[u8; 16]>,
+    // Playback rate of the pattern, as set by FX3A.
+    pub pitch: u8
+}
+
+/// A pull-based sample source. The host updates the voice
+/// each frame, and the frontend pulls mono samples from it
+/// at its own rate. The samples depend only on the voices
+/// and the number pulled, so they're the same every run.
+pub struct Synth {
+    sample_rate: u32,
+    voice: Voice,
+    // Position in the pattern in bits, or
+    // in the square wave's cycle in turns.
+    phase: f64
+}
+
+/// Collects the audio of a session a frame at a time,
+/// without playing it, so it can be saved as a WAV file.
+pub struct Recorder {
+    synth: Synth,
+    samples: Vec<f32>,
+    // Fraction of a sample left over from
+    // frames that don't divide the rate evenly.
+    remainder: f64
+}
+
+/// Bits per second that an XO-CHIP pattern plays
+/// at for a pitch. 64 is 4000 and every 48 is an octave.
+pub fn pattern_rate(pitch: u8) -> f64 {
+    4000.0 * 2f64.powf((pitch as f64 - 64.0) / 48.0)
+}
+
+impl From<&Chip8> for Voice {
+    /// XO-CHIP programs play their pattern, once they've
+    /// loaded one that isn't silent. Everything else
+    /// plays the square wave.
+    fn from(cpu: &Chip8) -> Voice {
+        let xo = cpu.platform >= Platform::XoChip;
+        let loaded = cpu.pattern.iter().any(|&byte| byte != 0);
+
+        Voice {
+            playing: cpu.sound > 0,
+            pattern: if xo && loaded { Some(cpu.pattern) } else { None },
+            pitch: cpu.pitch
+        }
+    }
+}
+
+impl Synth {
+    pub fn new(sample_rate: u32) -> Synth {
+        Synth {
+            sample_rate,
+            voice: Voice { playing: false, pattern: None, pitch: DEFAULT_PITCH },
+            phase: 0.0
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Change what's being played. The phase carries on,
+    /// so there's no click when only the pitch changes.
+    pub fn set_voice(&mut self, voice: Voice) {
+        if voice.pattern.is_some() != self.voice.pattern.is_some() {
+            self.phase = 0.0
+        }
+
+        self.voice = voice
+    }
+
+    /// Make the next sample. Silence is 0.0.
+    pub fn next_sample(&mut self) -> f32 {
+        if !self.voice.playing {
+            return 0.0
+        }
+
+        let rate = self.sample_rate as f64;
+
+        let high = match self.voice.pattern {
+            Some(ref pattern) => {
+                let bit = self.phase as usize % 128;
+                self.phase = (self.phase + pattern_rate(self.voice.pitch) / rate) % 128.0;
+                pattern[bit / 8] & (0x80 >> (bit % 8)) != 0
+            },
+
+            None => {
+                let high = self.phase < 0.5;
+                self.phase = (self.phase + TONE / rate) % 1.0;
+                high
+            }
+        };
+
+        if high { VOLUME } else { -VOLUME }
+    }
+
+    /// Fill a buffer with the next samples.
+    pub fn fill(&mut self, out: &mut [f32]) {
+        for sample in out.iter_mut() {
+            *sample = self.next_sample()
+        }
+    }
+}
+
+impl Iterator for Synth {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        Some(self.next_sample())
+    }
+}
+
+impl Recorder {
+    pub fn new(sample_rate: u32) -> Recorder {
+        Recorder {
+            synth: Synth::new(sample_rate),
+            samples: vec![],
+            remainder: 0.0
+        }
+    }
+
+    /// Record one frame of audio, as the interpreter is now.
+    /// Call this after each run_frame().
+    pub fn record_frame(&mut self, cpu: &Chip8) {
+        self.synth.set_voice(Voice::from(cpu));
+
+        let count = self.synth.sample_rate() as f64 / FRAME_RATE as f64 + self.remainder;
+        self.remainder = count.fract();
+
+        for _ in 0 .. count as usize {
+            let sample = self.synth.next_sample();
+            self.samples.push(sample)
+        }
+    }
+
+    /// The samples recorded so far.
+    pub fn samples(&self) -> &[f32] {
+        &self.samples
+    }
+
+    /// Save everything recorded as a WAV file.
+    pub fn save_wav<P: AsRef<Path>>(&self, path: P) -> IOResult<()> {
+        save_wav(&self.samples, self.synth.sample_rate(), path)
+    }
+}
+
+/// Encode mono samples as a 16 bit PCM WAV file.
+pub fn write_wav<W: Write>(samples: &[f32], sample_rate: u32, mut out: W) -> IOResult<()> {
+    let length = samples.len() as u32 * 2;
+
+    out.write_all(b"RIFF")?;
+    out.write_all(&(36 + length).to_le_bytes())?;
+    out.write_all(b"WAVE")?;
+
+    // Format: PCM, one channel, two bytes per sample.
+    out.write_all(b"fmt ")?;
+    out.write_all(&16u32.to_le_bytes())?;
+    out.write_all(&1u16.to_le_bytes())?;
+    out.write_all(&1u16.to_le_bytes())?;
+    out.write_all(&sample_rate.to_le_bytes())?;
+    out.write_all(&(sample_rate * 2).to_le_bytes())?;
+    out.write_all(&2u16.to_le_bytes())?;
+    out.write_all(&16u16.to_le_bytes())?;
+
+    out.write_all(b"data")?;
+    out.write_all(&length.to_le_bytes())?;
+
+    for &sample in samples {
+        let value = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+        out.write_all(&value.to_le_bytes())?;
+    }
+
+    out.flush()
+}
+
+/// Save mono samples to a WAV file.
+pub fn save_wav<P: AsRef<Path>>(samples: &[f32], sample_rate: u32, path: P) -> IOResult<()> {
+    let file = File::create(path)?;
+    write_wav(samples, sample_rate, BufWriter::new(file))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quirks::Quirks;
+
+    /// Loads a pattern of alternating nibbles, sets
+    /// the sound timer to 30 and waits forever.
+    const PATTERN_PROGRAM: [u8; 26] = [
+        0xA2, 0x0A, 0xF0, 0x02, 0x60, 0x1E, 0xF0, 0x18, 0x12, 0x08,
+        0xF0, 0x0F, 0xF0, 0x0F, 0xF0, 0x0F, 0xF0, 0x0F,
+        0xF0, 0x0F, 0xF0, 0x0F, 0xF0, 0x0F, 0xF0, 0x0F
+    ];
+
+    fn record(frames: usize) -> Vec<u8> {
+        let mut cpu = Chip8::new(Quirks::xochip(), None, None);
+        cpu.platform = Platform::XoChip;
+        cpu.load_rom(&PATTERN_PROGRAM).unwrap();
+
+        let mut recorder = Recorder::new(SAMPLE_RATE);
+
+        for _ in 0 .. frames {
+            cpu.run_frame().unwrap();
+            recorder.record_frame(&cpu)
+        }
+
+        let mut wav = vec![];
+        write_wav(recorder.samples(), SAMPLE_RATE, &mut wav).unwrap();
+        wav
+    }
+
+    #[test]
+    fn pattern_plays_bit_by_bit() {
+        // At pitch 64 the pattern plays 4000 bits a second,
+        // which is two samples a bit at 8000 samples a second.
+        let mut synth = Synth::new(8000);
+        let mut pattern = [0; 16];
+        pattern[0] = 0xF0;
+        pattern[1] = 0xA0;
+        synth.set_voice(Voice { playing: true, pattern: Some(pattern), pitch: 64 });
+
+        let bits: Vec<bool> = synth.take(24).step_by(2).map(|sample| sample > 0.0).collect();
+        let expected = [true, true, true, true, false, false, false, false, true, false, true, false];
+        assert_eq!(bits, expected);
+    }
+
+    #[test]
+    fn recording_is_deterministic() {
+        let wav = record(40);
+        assert_eq!(wav, record(40));
+
+        // 40 frames at 60 a second, after the 44 byte header.
+        let samples = SAMPLE_RATE as usize * 40 / 60;
+        assert_eq!(wav.len(), 44 + samples * 2);
+        assert_eq!(&wav[.. 4], b"RIFF");
+        assert_eq!(&wav[36 .. 40], b"data");
+
+        // The pattern starts high, and the sound
+        // timer runs out after 30 frames.
+        let sample = |i: usize| i16::from_le_bytes([wav[44 + i * 2], wav[45 + i * 2]]);
+        let volume = (VOLUME * i16::MAX as f32) as i16;
+        assert_eq!(sample(0), volume);
+        assert_eq!(sample(samples / 40 * 35), 0);
+    }
+}
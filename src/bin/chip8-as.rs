@@ -0,0 +1,95 @@
+extern crate chip8;
+
+use std::env;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::process;
+use chip8::assemble_file;
+
+const USAGE: &str = "\
+usage: chip8-as [options] SOURCE
+
+options:
+    -o FILE       where to write the ROM (default SOURCE
+                  with the extension .ch8, needed if
+                  SOURCE already ends in .ch8)
+    --symbols     print the value of every label and constant";
+
+struct Options {
+    source: String,
+    output: String,
+    symbols: bool
+}
+
+/// Parse the command line, or explain what's wrong with it.
+fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Result<Options, String> {
+    let mut output = None;
+    let mut symbols = false;
+    let mut source = None;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" => output = Some(args.next().ok_or("-o needs a value".to_string())?),
+
+            "--symbols" => symbols = true,
+
+            "-h" | "--help" => return Err(String::new()),
+
+            _ if source.is_none() && !arg.starts_with('-') => source = Some(arg),
+
+            _ => return Err(format!("unexpected argument: {}", arg))
+        }
+    }
+
+    let source = source.ok_or("no source given".to_string())?;
+
+    // Don't write the ROM over its own source.
+    let output = match output {
+        Some(output) => output,
+        None => {
+            let output = Path::new(&source).with_extension("ch8");
+
+            if output == Path::new(&source) {
+                return Err(format!("{} would be overwritten, so give the ROM a name with -o", source))
+            }
+
+            output.to_string_lossy().into_owned()
+        }
+    };
+
+    Ok(Options { source, output, symbols })
+}
+
+fn main() {
+    let options = match parse_args(env::args().skip(1)) {
+        Ok(options) => options,
+        Err(message) => {
+            if !message.is_empty() {
+                eprintln!("chip8-as: {}", message);
+            }
+
+            eprintln!("{}", USAGE);
+            process::exit(2)
+        }
+    };
+
+    let assembly = match assemble_file(&options.source) {
+        Ok(assembly) => assembly,
+        Err(error) => {
+            eprintln!("chip8-as: {}", error);
+            process::exit(1)
+        }
+    };
+
+    if let Err(error) = File::create(&options.output).and_then(|mut file| file.write_all(&assembly.rom)) {
+        eprintln!("chip8-as: couldn't write {}: {}", options.output, error);
+        process::exit(1)
+    }
+
+    if options.symbols {
+        for (name, value) in &assembly.symbols {
+            println!("{:04X}  {}", value, name)
+        }
+    }
+}
@@ -0,0 +1,88 @@
+extern crate chip8;
+
+use std::env;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::process;
+use chip8::{disassemble, disassemble_source, Syntax};
+
+const USAGE: &str = "\
+usage: chip8-dis [options] ROM
+
+options:
+    --syntax S    cowgod or octo (default cowgod)
+    --source      follow the program's control flow, and print
+                  source with labels that assembles back into
+                  the ROM, rather than a listing";
+
+struct Options {
+    rom: String,
+    syntax: Syntax,
+    source: bool
+}
+
+/// Parse the command line, or explain what's wrong with it.
+fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Result<Options, String> {
+    let mut syntax = Syntax::Cowgod;
+    let mut source = false;
+    let mut rom = None;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--syntax" => {
+                let name = args.next().ok_or("--syntax needs a value".to_string())?;
+                syntax = Syntax::from_name(&name)
+                    .ok_or(format!("unknown syntax: {}", name))?
+            },
+
+            "--source" => source = true,
+
+            "-h" | "--help" => return Err(String::new()),
+
+            _ if rom.is_none() && !arg.starts_with("--") => rom = Some(arg),
+
+            _ => return Err(format!("unexpected argument: {}", arg))
+        }
+    }
+
+    let rom = rom.ok_or("no ROM given".to_string())?;
+    Ok(Options { rom, syntax, source })
+}
+
+fn main() {
+    let options = match parse_args(env::args().skip(1)) {
+        Ok(options) => options,
+        Err(message) => {
+            if !message.is_empty() {
+                eprintln!("chip8-dis: {}", message);
+            }
+
+            eprintln!("{}", USAGE);
+            process::exit(2)
+        }
+    };
+
+    let mut rom = vec![];
+    let path = &options.rom;
+
+    if let Err(error) = File::open(path).and_then(|mut file| file.read_to_end(&mut rom)) {
+        eprintln!("chip8-dis: couldn't read {}: {}", path, error);
+        process::exit(1)
+    }
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+
+    // Errors writing are ignored, as they're
+    // most likely from the output being closed.
+    if options.source {
+        let _ = out.write_all(disassemble_source(&rom, options.syntax).as_bytes());
+        return
+    }
+
+    for line in disassemble(&rom, options.syntax) {
+        if writeln!(out, "{}", line).is_err() {
+            break
+        }
+    }
+}
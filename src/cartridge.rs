@@ -0,0 +1,365 @@
+extern crate gif;
+
+use std::collections::BTreeMap;
+use std::io::{Error as IOError, ErrorKind, Result as IOResult};
+use cpu::{Chip8, Rom};
+use display::Palette;
+use octo;
+use quirks::{Platform, Quirks};
+
+/// Instructions per frame when a cartridge doesn't say.
+pub const DEFAULT_TICKRATE: usize = 20;
+
+/// Octo's colours for the background, plane 1, plane 2 and
+/// both planes, when a cartridge doesn't give its own.
+const DEFAULT_COLOURS: [[u8; 3]; 4] = [
+    [0x99, 0x66, 0x00],
+    [0xFF, 0xCC, 0x00],
+    [0xFF, 0x66, 0x00],
+    [0x66, 0x22, 0x00]
+];
+
+/// Largest program Octo allows before it needs XO-CHIP's memory.
+const CHIP8_MAX_SIZE: f64 = 3584.0;
+
+/// A program and the settings to run it with, from an Octo
+/// cartridge: a GIF image with its label drawn in the high
+/// bits of each pixel, and the payload in the low two bits.
+/// Read in order through every frame, four pixels make a byte,
+/// first pixel highest. The payload is a 4 byte big endian
+/// length, then that much JSON, as {"program": source,
+/// "options": {...}} with Octo's names for the options.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cartridge {
+    // The program, as Octo source.
+    pub program: String,
+    // Instructions per frame, Octo's tickrate.
+    pub speed: usize,
+    pub quirks: Quirks,
+    pub platform: Platform,
+    pub palette: Palette
+}
+
+/// A JSON value, as much as cartridges need.
+#[derive(Clone, Debug, PartialEq)]
+enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    Str(String),
+    Array(Vec<Json>),
+    Object(BTreeMap<String, Json>)
+}
+
+fn invalid<S: Into<String>>(message: S) -> IOError {
+    IOError::new(ErrorKind::InvalidData, message.into())
+}
+
+/// The error for a GIF that isn't a cartridge.
+fn no_payload() -> IOError {
+    invalid("the GIF has no Octo cartridge payload")
+}
+
+/// Whether a file should be read as a cartridge: any GIF
+/// image. Cartridge::decode says if it has no payload.
+pub fn is_cartridge(data: &[u8]) -> bool {
+    data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a")
+}
+
+/// Parses JSON from the text of a payload.
+struct Parser<'a> {
+    text: &'a [u8],
+    next: usize
+}
+
+impl<'a> Parser<'a> {
+    fn skip_whitespace(&mut self) {
+        while self.next < self.text.len() && self.text[self.next].is_ascii_whitespace() {
+            self.next += 1
+        }
+    }
+
+    fn peek(&mut self) -> Option<u8> {
+        self.skip_whitespace();
+        self.text.get(self.next).cloned()
+    }
+
+    fn expect(&mut self, byte: u8) -> IOResult<()> {
+        if self.peek() != Some(byte) {
+            return Err(invalid(format!("expected '{}' at byte {} of the payload", byte as char, self.next)))
+        }
+
+        self.next += 1;
+        Ok(())
+    }
+
+    fn literal(&mut self, word: &str, value: Json) -> IOResult<Json> {
+        if !self.text[self.next ..].starts_with(word.as_bytes()) {
+            return Err(invalid(format!("bad JSON at byte {} of the payload", self.next)))
+        }
+
+        self.next += word.len();
+        Ok(value)
+    }
+
+    fn value(&mut self) -> IOResult<Json> {
+        match self.peek() {
+            Some(b'{') => {
+                self.next += 1;
+                let mut object = BTreeMap::new();
+
+                if self.peek() == Some(b'}') {
+                    self.next += 1;
+                    return Ok(Json::Object(object))
+                }
+
+                loop {
+                    let key = self.string()?;
+                    self.expect(b':')?;
+                    object.insert(key, self.value()?);
+
+                    if self.peek() == Some(b',') {
+                        self.next += 1
+                    }
+
+                    else {
+                        self.expect(b'}')?;
+                        return Ok(Json::Object(object))
+                    }
+                }
+            },
+
+            Some(b'[') => {
+                self.next += 1;
+                let mut array = vec![];
+
+                if self.peek() == Some(b']') {
+                    self.next += 1;
+                    return Ok(Json::Array(array))
+                }
+
+                loop {
+                    array.push(self.value()?);
+
+                    if self.peek() == Some(b',') {
+                        self.next += 1
+                    }
+
+                    else {
+                        self.expect(b']')?;
+                        return Ok(Json::Array(array))
+                    }
+                }
+            },
+
+            Some(b'"') => self.string().map(Json::Str),
+            Some(b't') => self.literal("true", Json::Bool(true)),
+            Some(b'f') => self.literal("false", Json::Bool(false)),
+            Some(b'n') => self.literal("null", Json::Null),
+
+            Some(_) => {
+                let start = self.next;
+
+                while self.next < self.text.len() && b"+-.eE0123456789".contains(&self.text[self.next]) {
+                    self.next += 1
+                }
+
+                String::from_utf8_lossy(&self.text[start .. self.next]).parse().map(Json::Number)
+                    .map_err(|_| invalid(format!("bad JSON at byte {} of the payload", start)))
+            },
+
+            None => Err(invalid("the payload ends too soon"))
+        }
+    }
+
+    fn string(&mut self) -> IOResult<String> {
+        self.expect(b'"')?;
+        let mut bytes = vec![];
+
+        loop {
+            let byte = *self.text.get(self.next).ok_or_else(|| invalid("the payload ends too soon"))?;
+            self.next += 1;
+
+            match byte {
+                b'"' => break,
+
+                b'\\' => {
+                    let escape = *self.text.get(self.next).ok_or_else(|| invalid("the payload ends too soon"))?;
+                    self.next += 1;
+
+                    let c = match escape {
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'u' => {
+                            let mut unit = self.unicode()?;
+
+                            // Characters past the first plane
+                            // are written as surrogate pairs.
+                            if (0xD800 .. 0xDC00).contains(&unit) && self.text[self.next ..].starts_with(b"\\u") {
+                                self.next += 2;
+                                let low = self.unicode()?;
+                                unit = 0x10000 + ((unit - 0xD800) << 10) + (low.wrapping_sub(0xDC00) & 0x3FF)
+                            }
+
+                            std::char::from_u32(unit).unwrap_or('\u{FFFD}')
+                        },
+                        other => other as char
+                    };
+
+                    let mut buffer = [0; 4];
+                    bytes.extend_from_slice(c.encode_utf8(&mut buffer).as_bytes())
+                },
+
+                _ => bytes.push(byte)
+            }
+        }
+
+        String::from_utf8(bytes).map_err(|_| invalid("the payload isn't UTF-8"))
+    }
+
+    /// The four hex digits of a \u escape.
+    fn unicode(&mut self) -> IOResult<u32> {
+        let digits = self.text.get(self.next .. self.next + 4).ok_or_else(|| invalid("the payload ends too soon"))?;
+        self.next += 4;
+
+        u32::from_str_radix(&String::from_utf8_lossy(digits), 16)
+            .map_err(|_| invalid("bad \\u escape in the payload"))
+    }
+}
+
+/// Read a colour written as #RRGGBB.
+fn colour(text: &str) -> Option<[u8; 3]> {
+    let hex = text.strip_prefix('#')?;
+
+    if hex.len() != 6 {
+        return None
+    }
+
+    let value = u32::from_str_radix(hex, 16).ok()?;
+    Some([(value >> 16) as u8, (value >> 8) as u8, value as u8])
+}
+
+impl Cartridge {
+    /// Decode a cartridge from the bytes of a GIF file.
+    /// An ordinary GIF, without a length and a JSON
+    /// object in its low bits, is an InvalidData error.
+    pub fn decode(data: &[u8]) -> IOResult<Cartridge> {
+        let mut options = gif::DecodeOptions::new();
+        options.set_color_output(gif::ColorOutput::Indexed);
+
+        let mut decoder = options.read_info(data).map_err(|e| invalid(e.to_string()))?;
+        let mut payload = vec![];
+        let mut byte = 0;
+        let mut count = 0;
+
+        while let Some(frame) = decoder.read_next_frame().map_err(|e| invalid(e.to_string()))? {
+            for &pixel in frame.buffer.iter() {
+                byte = (byte << 2) | (pixel & 0x3);
+                count += 1;
+
+                if count % 4 == 0 {
+                    payload.push(byte)
+                }
+            }
+        }
+
+        if payload.len() < 4 {
+            return Err(no_payload())
+        }
+
+        let length = u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]) as usize;
+        let text = payload.get(4 .. 4 + length).ok_or_else(no_payload)?;
+        let mut parser = Parser { text, next: 0 };
+
+        // Any other GIF is unlikely to have
+        // a JSON object where the payload goes.
+        if parser.peek() != Some(b'{') {
+            return Err(no_payload())
+        }
+
+        match parser.value()? {
+            Json::Object(ref payload) => Cartridge::from_json(payload),
+            _ => Err(no_payload())
+        }
+    }
+
+    fn from_json(payload: &BTreeMap<String, Json>) -> IOResult<Cartridge> {
+        let program = match payload.get("program") {
+            Some(Json::Str(program)) => program.clone(),
+            _ => return Err(invalid("the cartridge has no program"))
+        };
+
+        let empty = BTreeMap::new();
+
+        let options = match payload.get("options") {
+            Some(Json::Object(options)) => options,
+            _ => &empty
+        };
+
+        let flag = |name: &str| options.get(name) == Some(&Json::Bool(true));
+
+        let number = |name: &str| match options.get(name) {
+            Some(&Json::Number(value)) => Some(value),
+            _ => None
+        };
+
+        // Octo's quirks are all off by default, which is
+        // how its own interpreter behaves.
+        let quirks = Quirks {
+            shift_vy: !flag("shiftQuirks"),
+            increment_index: !flag("loadStoreQuirks"),
+            jump_vx: flag("jumpQuirks"),
+            logic_reset_vf: flag("logicQuirks"),
+            wrap_sprites: !flag("clipQuirks"),
+            display_wait: flag("vBlankQuirks"),
+            flag_before_result: flag("vfOrderQuirks")
+        };
+
+        // Octo always runs the SUPER-CHIP instructions,
+        // and a larger program needs XO-CHIP's memory.
+        let platform = match number("maxSize") {
+            Some(size) if size > CHIP8_MAX_SIZE => Platform::XoChip,
+            _ => Platform::SuperChip
+        };
+
+        let mut palette = Palette::default();
+        let names = ["backgroundColor", "fillColor", "fillColor2", "blendColor"];
+
+        for (i, name) in names.iter().enumerate() {
+            palette.0[i] = match options.get(*name) {
+                Some(Json::Str(text)) => colour(text).unwrap_or(DEFAULT_COLOURS[i]),
+                _ => DEFAULT_COLOURS[i]
+            }
+        }
+
+        Ok(Cartridge {
+            program,
+            speed: number("tickrate").map(|rate| rate.max(1.0) as usize).unwrap_or(DEFAULT_TICKRATE),
+            quirks,
+            platform,
+            palette
+        })
+    }
+
+    /// Set up the interpreter as the cartridge says,
+    /// and give the renderer its colours.
+    pub fn configure(&self, cpu: &mut Chip8) {
+        cpu.speed = self.speed;
+        cpu.quirks = self.quirks;
+        cpu.platform = self.platform;
+
+        if let Some(ref mut renderer) = cpu.renderer {
+            renderer.set_palette(self.palette)
+        }
+    }
+
+    /// Compile the program.
+    pub fn compile(&self) -> IOResult<Rom> {
+        octo::compile(&self.program)
+            .map(|program| program.rom)
+            .map_err(|e| invalid(format!("the cartridge's program doesn't compile: {}", e)))
+    }
+}
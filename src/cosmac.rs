@@ -0,0 +1,321 @@
+/// The RCA CDP1802 processor of the COSMAC VIP, for running
+/// the machine code subroutines that 0NNN calls. It works
+/// directly on the interpreter's memory, and has no devices:
+/// the EF lines read low, and input reads zero.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Cosmac {
+    // R0 to RF, the 16 bit scratchpad registers.
+    pub r: [u16; 16],
+    // The accumulator.
+    pub d: u8,
+    // Data flag, the carry out of arithmetic and shifts.
+    pub df: bool,
+    // The register that is the program counter.
+    pub p: u8,
+    // The register that points at the data operand.
+    pub x: u8,
+    // X and P as saved by MARK, or by an interrupt.
+    pub t: u8,
+    // Interrupt enable.
+    pub ie: bool,
+    // The Q output, which drives the VIP's speaker.
+    pub q: bool
+}
+
+/// An opcode the 1802 doesn't define. Only 68,
+/// which the later 1804 uses as a prefix.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Undefined {
+    pub address: u16,
+    pub opcode: u8
+}
+
+impl Cosmac {
+    /// The processor as it is after reset,
+    /// with R0 as the program counter.
+    pub fn new() -> Cosmac {
+        Cosmac {
+            r: [0; 16],
+            d: 0,
+            df: false,
+            p: 0,
+            x: 0,
+            t: 0,
+            ie: true,
+            q: false
+        }
+    }
+
+    /// Execute one instruction, returning the number
+    /// of machine cycles it took: 3 for the long branches
+    /// and skips, and 2 for everything else. Addresses wrap
+    /// around the given memory, whose length must be a
+    /// power of two, as the VIP's RAM repeats through the
+    /// address space.
+    pub fn step(&mut self, memory: &mut [u8]) -> Result<u32, Undefined> {
+        let mask = memory.len() - 1;
+
+        macro_rules! read {
+            ($addr:expr) => { memory[$addr as usize & mask] }
+        }
+
+        macro_rules! write {
+            ($addr:expr, $value:expr) => {{
+                let value = $value;
+                memory[$addr as usize & mask] = value
+            }}
+        }
+
+        let (p, x) = (self.p as usize, self.x as usize);
+        let address = self.r[p];
+        let opcode = read!(address);
+        let n = (opcode & 0xF) as usize;
+        self.r[p] = address.wrapping_add(1);
+
+        // Reads the immediate byte after the opcode.
+        macro_rules! immediate {
+            () => {{
+                let value = read!(self.r[p]);
+                self.r[p] = self.r[p].wrapping_add(1);
+                value
+            }}
+        }
+
+        match opcode >> 4 {
+            // IDL waits for DMA or an interrupt,
+            // neither of which ever come.
+            0x0 if n == 0 => {},
+
+            // LDN: load via N.
+            0x0 => self.d = read!(self.r[n]),
+
+            // INC and DEC.
+            0x1 => self.r[n] = self.r[n].wrapping_add(1),
+            0x2 => self.r[n] = self.r[n].wrapping_sub(1),
+
+            // Short branches, to an address in the
+            // same page as the byte after the opcode.
+            0x3 => {
+                let taken = match n & 0x7 {
+                    0x0 => true,
+                    0x1 => self.q,
+                    0x2 => self.d == 0,
+                    0x3 => self.df,
+                    _ => false
+                };
+
+                // 38 to 3F branch if the condition is false,
+                // so 38 is a skip over the next byte.
+                if taken != (n >= 0x8) {
+                    let target = read!(self.r[p]);
+                    self.r[p] = (self.r[p] & 0xFF00) | target as u16
+                }
+
+                else {
+                    self.r[p] = self.r[p].wrapping_add(1)
+                }
+            },
+
+            // LDA: load and advance.
+            0x4 => {
+                self.d = read!(self.r[n]);
+                self.r[n] = self.r[n].wrapping_add(1)
+            },
+
+            // STR: store via N.
+            0x5 => write!(self.r[n], self.d),
+
+            0x6 => match n {
+                // IRX: increment RX.
+                0x0 => self.r[x] = self.r[x].wrapping_add(1),
+
+                // OUT: the byte at RX goes nowhere.
+                0x1 ..= 0x7 => self.r[x] = self.r[x].wrapping_add(1),
+
+                0x8 => return Err(Undefined { address, opcode }),
+
+                // INP: nothing is ever on the bus.
+                _ => {
+                    self.d = 0;
+                    write!(self.r[x], 0)
+                }
+            },
+
+            0x7 => match n {
+                // RET and DIS: restore X and P from M(RX).
+                0x0 | 0x1 => {
+                    let saved = read!(self.r[x]);
+                    self.r[x] = self.r[x].wrapping_add(1);
+                    self.x = saved >> 4;
+                    self.p = saved & 0xF;
+                    self.ie = n == 0x0
+                },
+
+                // LDXA: load via X and advance.
+                0x2 => {
+                    self.d = read!(self.r[x]);
+                    self.r[x] = self.r[x].wrapping_add(1)
+                },
+
+                // STXD: store via X and decrement.
+                0x3 => {
+                    write!(self.r[x], self.d);
+                    self.r[x] = self.r[x].wrapping_sub(1)
+                },
+
+                // ADC, SDB, SMB and their immediates.
+                0x4 | 0xC => {
+                    let operand = if n == 0x4 { read!(self.r[x]) } else { immediate!() };
+                    self.add(operand, self.df)
+                },
+
+                0x5 | 0xD => {
+                    let operand = if n == 0x5 { read!(self.r[x]) } else { immediate!() };
+                    self.subtract(operand, self.d, self.df)
+                },
+
+                0x7 | 0xF => {
+                    let operand = if n == 0x7 { read!(self.r[x]) } else { immediate!() };
+                    self.subtract(self.d, operand, self.df)
+                },
+
+                // SHRC: rotate right through DF.
+                0x6 => {
+                    let carry = self.d & 0x1 != 0;
+                    self.d = (self.d >> 1) | ((self.df as u8) << 7);
+                    self.df = carry
+                },
+
+                // SHLC: rotate left through DF.
+                0xE => {
+                    let carry = self.d & 0x80 != 0;
+                    self.d = (self.d << 1) | self.df as u8;
+                    self.df = carry
+                },
+
+                // SAV: save T.
+                0x8 => write!(self.r[x], self.t),
+
+                // MARK: save X and P in T and on the
+                // stack at R2, then make X equal to P.
+                0x9 => {
+                    self.t = (self.x << 4) | self.p;
+                    write!(self.r[2], self.t);
+                    self.x = self.p;
+                    self.r[2] = self.r[2].wrapping_sub(1)
+                },
+
+                // REQ and SEQ.
+                0xA => self.q = false,
+                _ => self.q = true
+            },
+
+            // GLO, GHI, PLO and PHI.
+            0x8 => self.d = self.r[n] as u8,
+            0x9 => self.d = (self.r[n] >> 8) as u8,
+            0xA => self.r[n] = (self.r[n] & 0xFF00) | self.d as u16,
+            0xB => self.r[n] = (self.r[n] & 0x00FF) | ((self.d as u16) << 8),
+
+            // Long branches and skips.
+            0xC => {
+                let condition = match n & 0x3 {
+                    0x0 => true,
+                    0x1 => self.q,
+                    0x2 => self.d == 0,
+                    _ => self.df
+                };
+
+                match n {
+                    // NOP.
+                    0x4 => {},
+
+                    // LSIE: skip if interrupts are enabled.
+                    0xC => if self.ie {
+                        self.r[p] = self.r[p].wrapping_add(2)
+                    },
+
+                    // LBR, LBQ, LBZ and LBDF branch if the
+                    // condition holds, C8 to CB if it doesn't.
+                    0x0 ..= 0x3 | 0x8 ..= 0xB => {
+                        if condition == (n < 0x8) {
+                            let high = read!(self.r[p]) as u16;
+                            let low = read!(self.r[p].wrapping_add(1)) as u16;
+                            self.r[p] = (high << 8) | low
+                        }
+
+                        else {
+                            self.r[p] = self.r[p].wrapping_add(2)
+                        }
+                    },
+
+                    // LSNQ, LSNZ and LSNF skip if the condition
+                    // doesn't hold, LSQ, LSZ and LSDF if it does.
+                    _ => if condition == (n >= 0xC) {
+                        self.r[p] = self.r[p].wrapping_add(2)
+                    }
+                }
+
+                return Ok(3)
+            },
+
+            // SEP and SEX.
+            0xD => self.p = n as u8,
+            0xE => self.x = n as u8,
+
+            _ => {
+                // The immediate forms take their
+                // operand from the program instead.
+                let operand = if n >= 0x8 && n != 0xE {
+                    immediate!()
+                } else {
+                    read!(self.r[x])
+                };
+
+                match n & 0x7 {
+                    // LDX and LDI.
+                    0x0 => self.d = operand,
+                    0x1 => self.d |= operand,
+                    0x2 => self.d &= operand,
+                    0x3 => self.d ^= operand,
+                    0x4 => self.add(operand, false),
+                    0x5 => self.subtract(operand, self.d, true),
+                    0x7 => self.subtract(self.d, operand, true),
+
+                    // SHR and SHL.
+                    _ if n == 0x6 => {
+                        self.df = self.d & 0x1 != 0;
+                        self.d >>= 1
+                    },
+
+                    _ => {
+                        self.df = self.d & 0x80 != 0;
+                        self.d <<= 1
+                    }
+                }
+            }
+        }
+
+        Ok(2)
+    }
+
+    /// D = D + operand + carry, with DF set on carry out.
+    fn add(&mut self, operand: u8, carry: bool) {
+        let sum = self.d as u16 + operand as u16 + carry as u16;
+        self.d = sum as u8;
+        self.df = sum > 0xFF
+    }
+
+    /// D = a - b, less one if borrowing in. DF is set
+    /// when there is no borrow out, as the 1802 does.
+    fn subtract(&mut self, a: u8, b: u8, no_borrow: bool) {
+        let difference = a as i16 - b as i16 - !no_borrow as i16;
+        self.d = difference as u8;
+        self.df = difference >= 0
+    }
+}
+
+impl Default for Cosmac {
+    fn default() -> Cosmac {
+        Cosmac::new()
+    }
+}
@@ -4,23 +4,136 @@ extern crate rand;
 use std::io::prelude::*;
 use std::io::Result as IOResult;
 use std::io::Error as IOError;
-use std::io::ErrorKind;
+use std::error::Error;
+use std::fmt;
 use std::fs::File;
 use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
 use self::rand::Rng;
 use self::rand::thread_rng;
+use instruction::Instruction;
+use cosmac::Cosmac;
+use timing::{vip_cycles, VIP_PROGRAM_CYCLES};
+use quirks::{Platform, Quirks};
+use display::{Framebuffer, Palette, Region, PLANES};
+use flags::{rom_hash, FlagStore, FLAG_COUNT};
+use cartridge::{is_cartridge, Cartridge};
 
 pub type Rom = Vec<u8>;
 pub type Opcode = u16;
 
+/// Address of the built-in hex font, in the
+/// interpreter area below the program.
+pub const FONT_ADDRESS: usize = 0x50;
+
+/// Height in bytes of each glyph in the hex font.
+pub const FONT_HEIGHT: usize = 5;
+
+/// The 4x5 glyphs for the digits 0 to F, in order.
+pub const FONT: [u8; 16 * FONT_HEIGHT] = [
+    0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
+    0x20, 0x60, 0x20, 0x20, 0x70, // 1
+    0xF0, 0x10, 0xF0, 0x80, 0xF0, // 2
+    0xF0, 0x10, 0xF0, 0x10, 0xF0, // 3
+    0x90, 0x90, 0xF0, 0x10, 0x10, // 4
+    0xF0, 0x80, 0xF0, 0x10, 0xF0, // 5
+    0xF0, 0x80, 0xF0, 0x90, 0xF0, // 6
+    0xF0, 0x10, 0x20, 0x40, 0x40, // 7
+    0xF0, 0x90, 0xF0, 0x90, 0xF0, // 8
+    0xF0, 0x90, 0xF0, 0x10, 0xF0, // 9
+    0xF0, 0x90, 0xF0, 0x90, 0x90, // A
+    0xE0, 0x90, 0xE0, 0x90, 0xE0, // B
+    0xF0, 0x80, 0x80, 0x80, 0xF0, // C
+    0xE0, 0x90, 0x90, 0x90, 0xE0, // D
+    0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
+    0xF0, 0x80, 0xF0, 0x80, 0x80  // F
+];
+
+/// Rate at which run() executes frames.
+pub const FRAME_RATE: u32 = 60;
+
+/// Rate at which the delay and sound timers count down.
+pub const TIMER_RATE: u32 = 60;
+
+/// Where programs are loaded, and start running.
+pub const PROGRAM_ADDRESS: usize = 0x200;
+
+/// Maximum depth of the call stack.
+pub const STACK_SIZE: usize = 16;
+
+/// Bytes of memory addressable by CHIP-8 and SUPER-CHIP.
+pub const MEMORY_SIZE: usize = 0x1000;
+
+/// Bytes of memory addressable by XO-CHIP.
+pub const XO_MEMORY_SIZE: usize = 0x10000;
+
+/// Where the VIP interpreter keeps V0 to VF,
+/// which machine code subroutines expect to find.
+pub const VIP_REGISTERS: usize = 0xEF0;
+
+/// Top of the VIP interpreter's stack, where
+/// R2 points when machine code is called.
+pub const VIP_STACK: usize = 0xECF;
+
+/// Where the VIP keeps its 64x32 display,
+/// one bit per pixel and 8 bytes per row.
+pub const VIP_DISPLAY: usize = 0xF00;
+
+/// Most 1802 instructions a machine code subroutine
+/// may execute before it's assumed to be stuck.
+pub const MACHINE_CODE_LIMIT: usize = 1_000_000;
+
+/// Pitch of the audio pattern until FX3A sets it,
+/// which plays the pattern at 4000 bits per second.
+pub const DEFAULT_PITCH: u8 = 64;
+
+/// Address of the SUPER-CHIP large font, just after the hex font.
+pub const BIG_FONT_ADDRESS: usize = FONT_ADDRESS + FONT.len();
+
+/// Height in bytes of each glyph in the large font.
+pub const BIG_FONT_HEIGHT: usize = 10;
+
+/// The 8x10 glyphs for the digits 0 to F, in order.
+/// SUPER-CHIP only defines 0 to 9.
+pub const BIG_FONT: [u8; 16 * BIG_FONT_HEIGHT] = [
+    0x3C, 0x7E, 0xE7, 0xC3, 0xC3, 0xC3, 0xC3, 0xE7, 0x7E, 0x3C, // 0
+    0x18, 0x38, 0x58, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x3C, // 1
+    0x3E, 0x7F, 0xC3, 0x06, 0x0C, 0x18, 0x30, 0x60, 0xFF, 0xFF, // 2
+    0x3C, 0x7E, 0xC3, 0x03, 0x0E, 0x0E, 0x03, 0xC3, 0x7E, 0x3C, // 3
+    0x06, 0x0E, 0x1E, 0x36, 0x66, 0xC6, 0xFF, 0xFF, 0x06, 0x06, // 4
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFC, 0xFE, 0x03, 0xC3, 0x7E, 0x3C, // 5
+    0x3E, 0x7C, 0xE0, 0xC0, 0xFC, 0xFE, 0xC3, 0xC3, 0x7E, 0x3C, // 6
+    0xFF, 0xFF, 0x03, 0x06, 0x0C, 0x18, 0x30, 0x60, 0x60, 0x60, // 7
+    0x3C, 0x7E, 0xC3, 0xC3, 0x7E, 0x7E, 0xC3, 0xC3, 0x7E, 0x3C, // 8
+    0x3C, 0x7E, 0xC3, 0xC3, 0x7F, 0x3F, 0x03, 0x03, 0x3E, 0x7C, // 9
+    0x3C, 0x7E, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xC3, 0xC3, // A
+    0xFC, 0xFE, 0xC3, 0xC3, 0xFE, 0xFE, 0xC3, 0xC3, 0xFE, 0xFC, // B
+    0x3C, 0x7E, 0xC3, 0xC0, 0xC0, 0xC0, 0xC0, 0xC3, 0x7E, 0x3C, // C
+    0xFC, 0xFE, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFE, 0xFC, // D
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFC, 0xFC, 0xC0, 0xC0, 0xFF, 0xFF, // E
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFC, 0xFC, 0xC0, 0xC0, 0xC0, 0xC0  // F
+];
+
+/// Address of the font glyph for a hex digit.
+pub fn glyph_address(digit: u8) -> u16 {
+    (FONT_ADDRESS + (digit & 0xF) as usize * FONT_HEIGHT) as u16
+}
+
+/// Address of the large font glyph for a hex digit.
+pub fn big_glyph_address(digit: u8) -> u16 {
+    (BIG_FONT_ADDRESS + (digit & 0xF) as usize * BIG_FONT_HEIGHT) as u16
+}
+
 pub struct Chip8 {
     // V0 to VF, each one byte.
     pub registers: [u8; 16],
-    // Arbitrary sized as the stack is not
-    // accessed manually.
+    // Not addressable, but limited to
+    // STACK_SIZE return addresses.
     pub stack:     Vec<usize>,
-    // 0x1000 bytes of addressable memory.
-    pub memory:    [u8; 0x1000],
+    // Always XO_MEMORY_SIZE bytes, but only the first
+    // memory_size() are addressable on the platform.
+    pub memory:    Vec<u8>,
     // Address register, I.
     pub index:     u16,
     // Program counter.
@@ -29,18 +142,188 @@ pub struct Chip8 {
     pub delay:     u8,
     // Sound timer.
     pub sound:     u8,
+    // Number of instructions run_frame() executes.
+    pub speed:     usize,
+    // How the delay and sound timers are counted down.
+    pub timer_mode: TimerMode,
+    // How many instructions run_frame() executes.
+    pub timing: Timing,
+    // 1802 machine cycles taken by the instructions executed
+    // so far, by what they cost on the COSMAC VIP.
+    pub cycles: u64,
+    // Machine cycles left in this frame, with VIP timing.
+    // Negative when the last frame's final instruction
+    // ran past its end, so this one starts late.
+    cycles_left: i64,
+    // When the timers last ticked in wall clock mode.
+    last_tick:     Option<Instant>,
     // Screen
-    pub screen: [[bool; 64]; 32],
+    pub screen: Framebuffer,
+    // Keypad, true for each of 0x0 to 0xF that is held down.
+    pub keys: [bool; 16],
+    // Register to store the next released key in,
+    // set while FX0A is waiting for input.
+    pub waiting: Option<u8>,
+    // Set while DXYN is waiting for the display interrupt.
+    pub vblank: bool,
+    // Planes selected by FN01, one bit each,
+    // which are drawn on, scrolled and cleared.
+    pub planes: u8,
+    // XO-CHIP audio pattern, played one bit at a time
+    // while the sound timer is set.
+    pub pattern: [u8; 16],
+    // Playback rate of the pattern, set by FX3A.
+    pub pitch: u8,
+    // Behaviour that differs between interpreters.
+    pub quirks: Quirks,
+    // Instruction set extensions that are enabled.
+    pub platform: Platform,
+    // Whether 0NNN runs the RCA 1802 machine code at NNN,
+    // as the COSMAC VIP did. Otherwise it's invalid.
+    pub machine_code: bool,
+    // Set once 00FD has exited the program.
+    pub exited: bool,
+    // RPL user flags, saved and loaded by FX75 and FX85.
+    pub flags: [u8; FLAG_COUNT],
+    // Somewhere to keep the flags between sessions.
+    // Or, they're lost when the interpreter is dropped.
+    pub flag_store: Option<Box<dyn FlagStore>>,
+    // The last time the flag store couldn't load or save
+    // the flags, for the host to report. The flags in
+    // memory are still used, so the program runs on.
+    pub flag_store_error: Option<IOError>,
+    // Hash of the loaded ROM, which its flags are kept under.
+    rom_hash: Option<u64>,
+    // Whether the screen has changed since
+    // it was last presented.
+    changed: bool,
     // Something that implements Render for screen drawing.
     // Or, no screen.
-    pub renderer: Option<Box<Render>>
+    pub renderer: Option<Box<dyn Render>>,
+    // Something that implements Input for the keypad.
+    // Or, no keypad.
+    pub input: Option<Box<dyn Input>>
 }
 
 pub trait Render {
-    fn clear(&self, screen: &mut [[bool; 64]; 32]);
+    /// Show a complete frame. This is called at the
+    /// end of a frame, only if the screen has changed.
+    fn present(&mut self, frame: &Framebuffer);
+
+    /// Called for each region of the screen changed
+    /// by an instruction, before the next present().
+    fn dirty(&mut self, _region: Region) {}
+
+    /// Change the colours pixels are drawn in, for
+    /// renderers that have colour.
+    fn set_palette(&mut self, _palette: Palette) {}
+
+    /// The last error from presenting a frame, if there's
+    /// been one since this was called, for the host to
+    /// report. Renderers that can't fail have none.
+    fn take_error(&mut self) -> Option<String> {
+        None
+    }
+}
+
+/// A change in the state of one of the 16 hex keys.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyEvent {
+    Pressed(u8),
+    Released(u8)
+}
+
+pub trait Input {
+    /// Returns the next pending key event, if there is one.
+    fn poll(&mut self) -> Option<KeyEvent>;
+}
+
+/// How the delay and sound timers are counted down.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimerMode {
+    /// Timers tick once per run_frame(), or whenever
+    /// the host calls tick_timers(). This is deterministic.
+    Frame,
+    /// Timers tick at TIMER_RATE by the host's clock,
+    /// however often instructions are executed.
+    WallClock
+}
+
+/// How many instructions run_frame() executes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Timing {
+    /// The interpreter's speed in instructions,
+    /// however long they would really take.
+    Speed,
+    /// As many as the COSMAC VIP would fit in a frame,
+    /// charging each instruction its cost in machine cycles.
+    /// Waiting for the display interrupt ends the frame.
+    Vip
+}
+
+/// The state of the interpreter after executing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Status {
+    /// The next instruction is ready to execute.
+    Running,
+    /// FX0A is waiting for a key to be released.
+    WaitingForKey,
+    /// DXYN is waiting for the timers to tick,
+    /// with the display wait quirk enabled.
+    WaitingForFrame,
+    /// The program has exited, or is
+    /// stuck jumping to itself.
+    Halted
+}
+
+/// A fault raised while executing a program.
+/// Each variant carries the address of the faulting
+/// instruction and its opcode.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CpuError {
+    /// The opcode is not a known instruction.
+    InvalidOpcode { pc: usize, opcode: Opcode },
+    /// A return was executed with an empty stack.
+    StackUnderflow { pc: usize, opcode: Opcode },
+    /// A call was executed with a full stack.
+    StackOverflow { pc: usize, opcode: Opcode },
+    /// The instruction accessed memory past the end.
+    MemoryOutOfRange { pc: usize, opcode: Opcode, address: usize },
+    /// The program counter left addressable memory.
+    /// If the counter was already out of range
+    /// before fetching, the opcode is zero.
+    CounterOverflow { pc: usize, opcode: Opcode },
+    /// The machine code called by 0NNN executed an undefined
+    /// 1802 instruction at the address, or was still running
+    /// there after MACHINE_CODE_LIMIT instructions.
+    MachineCodeFault { pc: usize, opcode: Opcode, address: usize }
 }
 
-trait Parameters {
+impl fmt::Display for CpuError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CpuError::InvalidOpcode { pc, opcode } =>
+                write!(f, "invalid opcode {:04X} at {:03X}", opcode, pc),
+            CpuError::StackUnderflow { pc, opcode } =>
+                write!(f, "stack underflow by {:04X} at {:03X}", opcode, pc),
+            CpuError::StackOverflow { pc, opcode } =>
+                write!(f, "stack overflow by {:04X} at {:03X}", opcode, pc),
+            CpuError::MemoryOutOfRange { pc, opcode, address } =>
+                write!(f, "address {:X} out of range for {:04X} at {:03X}",
+                       address, opcode, pc),
+            CpuError::CounterOverflow { pc, opcode } =>
+                write!(f, "program counter overflow by {:04X} at {:03X}", opcode, pc),
+            CpuError::MachineCodeFault { pc, opcode, address } =>
+                write!(f, "machine code called by {:04X} at {:03X} failed at {:03X}",
+                       opcode, pc, address)
+        }
+    }
+}
+
+impl Error for CpuError {}
+
+/// Field accessors for the parts of an opcode.
+pub trait Parameters {
     fn nnn(&self) -> u16;
     fn nn(&self) -> u8;
     fn n(&self) -> u8;
@@ -75,27 +358,265 @@ impl Parameters for Opcode {
 }
 
 impl Chip8 {
-    pub fn new(renderer: Option<Box<Render>>) -> Chip8 {
+    pub fn new(quirks: Quirks,
+               renderer: Option<Box<dyn Render>>,
+               input: Option<Box<dyn Input>>) -> Chip8 {
+        let mut memory = vec![0; XO_MEMORY_SIZE];
+        memory[FONT_ADDRESS..(FONT_ADDRESS + FONT.len())].clone_from_slice(&FONT);
+        memory[BIG_FONT_ADDRESS..(BIG_FONT_ADDRESS + BIG_FONT.len())].clone_from_slice(&BIG_FONT);
+
         Chip8 {
             registers: [0; 16],
             stack: vec![],
-            memory: [0; 0x1000],
+            memory,
             index: 0,
-            counter: 0x200,
+            counter: PROGRAM_ADDRESS,
             delay: 0,
             sound: 0,
-            screen: [[false; 64]; 32],
-            renderer: renderer
+            speed: 10,
+            timer_mode: TimerMode::Frame,
+            timing: Timing::Speed,
+            cycles: 0,
+            cycles_left: 0,
+            last_tick: None,
+            screen: Framebuffer::new(),
+            keys: [false; 16],
+            waiting: None,
+            vblank: false,
+            planes: 1,
+            pattern: [0; 16],
+            pitch: DEFAULT_PITCH,
+            quirks,
+            platform: Platform::Chip8,
+            machine_code: false,
+            exited: false,
+            flags: [0; FLAG_COUNT],
+            flag_store: None,
+            flag_store_error: None,
+            rom_hash: None,
+            changed: true,
+            renderer,
+            input
+        }
+    }
+
+    /// Marks a key as held down.
+    pub fn press(&mut self, key: u8) {
+        self.keys[(key & 0xF) as usize] = true
+    }
+
+    /// Marks a key as released.
+    /// This completes an FX0A that is waiting for input.
+    pub fn release(&mut self, key: u8) {
+        let key = key & 0xF;
+        let held = self.keys[key as usize];
+        self.keys[key as usize] = false;
+
+        if held {
+            if let Some(register) = self.waiting.take() {
+                self.registers[register as usize] = key
+            }
+        }
+    }
+
+    /// Bytes of memory the platform can address.
+    pub fn memory_size(&self) -> usize {
+        if self.platform >= Platform::XoChip { XO_MEMORY_SIZE } else { MEMORY_SIZE }
+    }
+
+    /// Set VX to the result of an arithmetic instruction
+    /// and VF to its flag, in the order the quirks say.
+    fn set_with_flag(&mut self, x: u8, result: u8, flag: u8) {
+        if self.quirks.flag_before_result {
+            self.registers[0xF] = flag;
+            self.registers[x as usize] = result
+        }
+
+        else {
+            self.registers[x as usize] = result;
+            self.registers[0xF] = flag
+        }
+    }
+
+    /// Skip over the next instruction, which on
+    /// XO-CHIP may be the four byte F000 NNNN.
+    fn skip(&mut self) {
+        let pc = self.counter;
+        let long = self.platform >= Platform::XoChip &&
+                   pc + 1 < self.memory_size() &&
+                   self.memory[pc] == 0xF0 && self.memory[pc + 1] == 0x00;
+
+        self.counter += if long { 4 } else { 2 }
+    }
+
+    /// Run the 1802 subroutine at an address, as the VIP's
+    /// interpreter does for 0NNN. The interpreter's state is put
+    /// where the VIP keeps it, in memory and the 1802 registers,
+    /// and read back once the subroutine returns with D4.
+    fn call_machine_code(&mut self, address: u16, pc: usize, op: Opcode) -> Result<(), CpuError> {
+        let fault = |address: u16| CpuError::MachineCodeFault {
+            pc, opcode: op, address: address as usize
+        };
+
+        let mut cpu = Cosmac::new();
+        cpu.r[2] = VIP_STACK as u16;
+        cpu.r[3] = address;
+        cpu.r[5] = self.counter as u16;
+        cpu.r[6] = (VIP_REGISTERS + op.x() as usize) as u16;
+        cpu.r[7] = (VIP_REGISTERS + op.y() as usize) as u16;
+        cpu.r[8] = ((self.delay as u16) << 8) | self.sound as u16;
+        cpu.r[0xA] = self.index;
+        cpu.r[0xB] = VIP_DISPLAY as u16;
+        cpu.x = 2;
+        cpu.p = 3;
+
+        self.memory[VIP_REGISTERS .. VIP_REGISTERS + 16].copy_from_slice(&self.registers);
+
+        // Only the low resolution screen fits.
+        let display = !self.screen.hires();
+
+        if display {
+            let screen = &self.screen;
+
+            for (i, byte) in self.memory[VIP_DISPLAY .. VIP_DISPLAY + 0x100].iter_mut().enumerate() {
+                *byte = (0 .. 8).fold(0, |byte, bit| {
+                    (byte << 1) | (screen.pixel((i % 8) * 8 + bit, i / 8) & 1)
+                })
+            }
+        }
+
+        let size = self.memory_size();
+        let mut returned = false;
+
+        for _ in 0 .. MACHINE_CODE_LIMIT {
+            let cycles = cpu.step(&mut self.memory[.. size])
+                .map_err(|undefined| fault(undefined.address))?;
+
+            self.charge(cycles as u64);
+
+            if cpu.p == 4 {
+                returned = true;
+                break
+            }
+        }
+
+        if !returned {
+            return Err(fault(cpu.r[cpu.p as usize]))
+        }
+
+        self.registers.copy_from_slice(&self.memory[VIP_REGISTERS .. VIP_REGISTERS + 16]);
+        self.counter = cpu.r[5] as usize;
+        self.index = cpu.r[0xA];
+        self.delay = (cpu.r[8] >> 8) as u8;
+        self.sound = cpu.r[8] as u8;
+
+        if display {
+            let mut changed = false;
+
+            for i in 0 .. 0x100 {
+                let byte = self.memory[VIP_DISPLAY + i];
+
+                for bit in 0 .. 8 {
+                    let (x, y) = ((i % 8) * 8 + bit, i / 8);
+                    let lit = byte & (0x80 >> bit) != 0;
+
+                    if lit != (self.screen.pixel(x, y) & 1 != 0) {
+                        self.screen.toggle(x, y, 1);
+                        changed = true
+                    }
+                }
+            }
+
+            if changed {
+                let bounds = self.screen.bounds();
+                self.touch(bounds)
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Count machine cycles taken by the program
+    /// against the frame, as well as in total.
+    fn charge(&mut self, cycles: u64) {
+        self.cycles += cycles;
+
+        if self.timing == Timing::Vip {
+            self.cycles_left -= cycles as i64
+        }
+    }
+
+    /// Note that a region of the screen has changed.
+    fn touch(&mut self, region: Region) {
+        self.changed = true;
+
+        if let Some(ref mut renderer) = self.renderer {
+            renderer.dirty(region)
+        }
+    }
+
+    /// Hand the screen to the renderer, if it has
+    /// changed since it was last presented.
+    pub fn present(&mut self) {
+        if self.changed {
+            if let Some(ref mut renderer) = self.renderer {
+                renderer.present(&self.screen)
+            }
+
+            self.changed = false
+        }
+    }
+
+    /// Apply a key event, for hosts that
+    /// poll their input devices themselves.
+    pub fn key_event(&mut self, event: KeyEvent) {
+        match event {
+            KeyEvent::Pressed(key) => self.press(key),
+            KeyEvent::Released(key) => self.release(key)
+        }
+    }
+
+    /// Apply any pending events from the input device.
+    fn poll_input(&mut self) {
+        // The device is taken out while polling
+        // so that events can be applied to self.
+        if let Some(mut input) = self.input.take() {
+            while let Some(event) = input.poll() {
+                self.key_event(event)
+            }
+
+            self.input = Some(input)
         }
     }
     
-    pub fn emulate(&mut self, op: Opcode) {
-        macro_rules! not_implemented {
+    /// Execute a single opcode.
+    /// The counter is expected to already point past
+    /// the instruction, as it does when called from step().
+    pub fn emulate(&mut self, op: Opcode) -> Result<(), CpuError> {
+        use instruction::Instruction::*;
+
+        let pc = self.counter.wrapping_sub(2);
+
+        macro_rules! invalid {
             () => {
-                println!("{:#X} is not implemented!", op)
+                return Err(CpuError::InvalidOpcode { pc, opcode: op })
             }
         }
 
+        // Macro for checking a memory address.
+        // It evaluates to the address if it is in range.
+        macro_rules! address {
+            ($addr:expr) => {{
+                let address = $addr;
+                if address >= self.memory_size() {
+                    return Err(CpuError::MemoryOutOfRange {
+                        pc, opcode: op, address
+                    })
+                }
+                address
+            }}
+        }
+
         // Macro for reading a register.
         // It converts the index to the Index-compatible usize.
         macro_rules! register {
@@ -104,226 +625,692 @@ impl Chip8 {
             }
         }
         
-        match op & 0xF000 {
-            0x0000 => {
-                // Clears the screen.
-                if op == 0x00E0 {
-                    if let Some(ref renderer) = self.renderer {
-                        renderer.clear(&mut self.screen)
-                    }
-                }
-                
-                // Returns from a subroutine.
-                else if op == 0x00EE {
-                    self.counter = self.stack.pop().unwrap()
+        let instruction = match Instruction::decode(op) {
+            Ok(instruction) => instruction,
+            Err(_) => invalid!()
+        };
+
+        // Extensions are invalid on platforms that don't
+        // have them. The SUPER-CHIP ones in 0NNN are really
+        // calls to machine code, which aren't supported.
+        if instruction.platform() > self.platform {
+            invalid!()
+        }
+
+        match instruction {
+            // Clears the selected planes.
+            Clear => {
+                self.screen.clear_planes(self.planes);
+                let bounds = self.screen.bounds();
+                self.touch(bounds)
+            },
+
+            // Returns from a subroutine.
+            Return => {
+                match self.stack.pop() {
+                    Some(address) => self.counter = address,
+                    None => return Err(CpuError::StackUnderflow {
+                        pc, opcode: op
+                    })
                 }
-                
-                // Calls RCA 1802 program at the address.
-                else { not_implemented!() }
+            },
+
+            // Calls RCA 1802 program at the address.
+            Sys { nnn } => {
+                if !self.machine_code { invalid!() }
+                self.call_machine_code(nnn, pc, op)?
+            },
+
+            // Scrolls the selected planes down by N pixels.
+            ScrollDown { n } => {
+                self.screen.scroll_down(n as usize, self.planes);
+                let bounds = self.screen.bounds();
+                self.touch(bounds)
+            },
+
+            // Scrolls the selected planes up by N pixels.
+            ScrollUp { n } => {
+                self.screen.scroll_up(n as usize, self.planes);
+                let bounds = self.screen.bounds();
+                self.touch(bounds)
+            },
+
+            // Scrolls the selected planes right by 4 pixels.
+            ScrollRight => {
+                self.screen.scroll_right(4, self.planes);
+                let bounds = self.screen.bounds();
+                self.touch(bounds)
+            },
+
+            // Scrolls the selected planes left by 4 pixels.
+            ScrollLeft => {
+                self.screen.scroll_left(4, self.planes);
+                let bounds = self.screen.bounds();
+                self.touch(bounds)
+            },
+
+            // Exits the interpreter.
+            Exit => {
+                self.exited = true
+            },
+
+            // Switches to 64x32 low resolution.
+            LowRes => {
+                self.screen.set_hires(false);
+                let bounds = self.screen.bounds();
+                self.touch(bounds)
+            },
+
+            // Switches to 128x64 high resolution.
+            HighRes => {
+                self.screen.set_hires(true);
+                let bounds = self.screen.bounds();
+                self.touch(bounds)
             },
 
             // Jumps to address.
-            0x1000 => {
-                self.counter = op.nnn() as usize
+            Jump { nnn } => {
+                self.counter = nnn as usize
             },
 
             // Calls subroutine at address.
-            0x2000 => {
+            Call { nnn } => {
+                if self.stack.len() >= STACK_SIZE {
+                    return Err(CpuError::StackOverflow { pc, opcode: op })
+                }
+
                 self.stack.push(self.counter);
-                self.counter = op.nnn() as usize
+                self.counter = nnn as usize
             },
 
             // Skips the next instruction
             // if VX equals NN.
-            0x3000 => {
-                if register!(op.x()) == op.nn() {
-                    self.counter += 2
+            SkipEqImm { x, nn } => {
+                if register!(x) == nn {
+                    self.skip()
                 }
             },
 
             // Skips the next instruction
             // if VX doesn't equal NN.
-            0x4000 => {
-                if register!(op.x()) != op.nn() {
-                    self.counter += 2
+            SkipNeImm { x, nn } => {
+                if register!(x) != nn {
+                    self.skip()
                 }
             },
 
             // Skips the next instruction
             // if VX equals VY.
-            0x5000 => {
-                if register!(op.x()) == register!(op.y()) {
-                    self.counter += 2
+            SkipEq { x, y } => {
+                if register!(x) == register!(y) {
+                    self.skip()
+                }
+            },
+
+            // Stores VX to VY in memory starting at I, in
+            // that order even if X is after Y. I is unchanged.
+            SaveRange { x, y } => {
+                let count = (x as isize - y as isize).unsigned_abs() + 1;
+
+                for i in 0 .. count {
+                    let register = if x <= y { x as usize + i } else { x as usize - i };
+                    let pos = address!(self.index as usize + i);
+                    self.memory[pos] = self.registers[register]
+                }
+            },
+
+            // Loads VX to VY from memory starting at I,
+            // in the same order as SaveRange.
+            LoadRange { x, y } => {
+                let count = (x as isize - y as isize).unsigned_abs() + 1;
+
+                for i in 0 .. count {
+                    let register = if x <= y { x as usize + i } else { x as usize - i };
+                    let pos = address!(self.index as usize + i);
+                    self.registers[register] = self.memory[pos]
                 }
             },
 
             // Sets VX to NN.
-            0x6000 => {
-                let vx = op.x();
-                register!(vx) = op.nn()
+            LoadImm { x, nn } => {
+                register!(x) = nn
             },
 
             // Adds NN to VX.
-            0x7000 => {
-                let vx = op.x();
+            AddImm { x, nn } => {
                 let new = {
-                    register!(vx).wrapping_add(op.nn())
+                    register!(x).wrapping_add(nn)
                 };
                 
-                register!(vx) = new
+                register!(x) = new
             },
 
-            0x8000 => {
-                let mode = op.n();
+            Load { x, y } => {
+                let vy = register!(y);
+                register!(x) = vy;
+            },
 
-                if mode == 0x0 {
-                    let vy = register!(op.y());
-                    register!(op.x()) = vy;
-                }
+            Or { x, y } => {
+                let vx = register!(x);
+                let vy = register!(y);
+                register!(x) = vx | vy;
 
-                else if mode == 0x1 {
-                    let vx = register!(op.x());
-                    let vy = register!(op.y());
-                    register!(op.x()) = vx | vy;
+                if self.quirks.logic_reset_vf {
+                    register!(0xF) = 0
                 }
+            },
 
-                else if mode == 0x2 {
-                    let vx = register!(op.x());
-                    let vy = register!(op.y());
-                    register!(op.x()) = vx & vy;
-                }
+            And { x, y } => {
+                let vx = register!(x);
+                let vy = register!(y);
+                register!(x) = vx & vy;
 
-                else if mode == 0x3 {
-                    let vx = register!(op.x());
-                    let vy = register!(op.y());
-                    register!(op.x()) = vx ^ vy;
+                if self.quirks.logic_reset_vf {
+                    register!(0xF) = 0
                 }
+            },
 
-                else if mode == 0x4 {
+            Xor { x, y } => {
+                let vx = register!(x);
+                let vy = register!(y);
+                register!(x) = vx ^ vy;
 
+                if self.quirks.logic_reset_vf {
+                    register!(0xF) = 0
                 }
+            },
+
+            // The arithmetic instructions write VF after the
+            // result, so VF as an operand is overwritten by the
+            // flag just as it is on real hardware, unless the
+            // quirks say otherwise.
+
+            // Adds VY to VX. VF is set on carry.
+            Add { x, y } => {
+                let vx = register!(x);
+                let vy = register!(y);
+                let (result, carry) = vx.overflowing_add(vy);
+                self.set_with_flag(x, result, carry as u8)
+            },
+
+            // Subtracts VY from VX. VF is cleared on borrow.
+            Sub { x, y } => {
+                let vx = register!(x);
+                let vy = register!(y);
+                let (result, borrow) = vx.overflowing_sub(vy);
+                self.set_with_flag(x, result, !borrow as u8)
+            },
+
+            // Shifts VX (or VY, by quirk) right by one
+            // into VX. VF is set to the bit shifted out.
+            ShiftRight { x, y } => {
+                let source = if self.quirks.shift_vy { y } else { x };
+                let value = register!(source);
+                self.set_with_flag(x, value >> 1, value & 0x1)
+            },
 
-                else { not_implemented!() }
+            // Sets VX to VY minus VX. VF is cleared on borrow.
+            SubReverse { x, y } => {
+                let vx = register!(x);
+                let vy = register!(y);
+                let (result, borrow) = vy.overflowing_sub(vx);
+                self.set_with_flag(x, result, !borrow as u8)
+            },
+
+            // Shifts VX (or VY, by quirk) left by one
+            // into VX. VF is set to the bit shifted out.
+            ShiftLeft { x, y } => {
+                let source = if self.quirks.shift_vy { y } else { x };
+                let value = register!(source);
+                self.set_with_flag(x, value << 1, value >> 7)
             },
 
             // Skips the next instruction
             // if VX doesn't equal VY.
-            0x9000 => {
-                not_implemented!()
+            SkipNe { x, y } => {
+                if register!(x) != register!(y) {
+                    self.skip()
+                }
             },
 
             // Sets I to the address NNN.
-            0xA000 => {
-                self.index = op.nnn()
+            LoadIndex { nnn } => {
+                self.index = nnn
             },
 
-            // Jumps to the address NNN plus V0.
-            0xB000 => {
-                let address = op.nnn() + (register!(0) as u16);
+            // Jumps to the address NNN plus V0,
+            // or XNN plus VX by quirk.
+            JumpOffset { nnn } => {
+                let offset = if self.quirks.jump_vx {
+                    register!(nnn >> 8)
+                } else {
+                    register!(0)
+                };
+
+                let address = nnn + (offset as u16);
                 self.counter = address as usize
             },
 
             // Sets VX to the result of a bitwise
             // AND operation on a random number and NN.
-            0xC000 => {
+            Random { x, nn } => {
                 let rn = thread_rng().gen::<u8>();
-                register!(op.x()) = rn & op.nn() 
+                register!(x) = rn & nn
             },
 
-            // Weird sprite stuff.
-            0xD000 => {
-                not_implemented!()
-            },
+            // Draws an 8 pixel wide, N pixel tall sprite
+            // read from I at (VX, VY) by XORing it onto the
+            // screen. VF is set if any pixel is turned off.
+            // On SUPER-CHIP, DXY0 draws a 16x16 sprite
+            // stored as two bytes per row, at either resolution.
+            // With several planes selected, a sprite is read
+            // for each in turn, starting with plane 1.
+            Draw { x, y, n } => {
+                let big = n == 0 && self.platform >= Platform::SuperChip;
+                let (columns, rows) = if big { (16, 16) } else { (8, n as usize) };
+                let stride = columns / 8;
+                let mut start = self.index as usize;
 
-            0xE000 => {
-                not_implemented!()
-            },
+                // The starting position always wraps around, but
+                // the sprite itself is clipped at the edges
+                // unless the wrap quirk is set.
+                let (width, height) = (self.screen.width(), self.screen.height());
+                let x = register!(x) as usize % width;
+                let y = register!(y) as usize % height;
+                let wrap = self.quirks.wrap_sprites;
+                register!(0xF) = 0;
 
-            0xF000 => {
-                let mode = op.nn();
-                
-                if mode == 0x07 {
-                    register!(op.x()) = self.delay
+                for plane in (0 .. PLANES).map(|i| 1 << i) {
+                    if self.planes & plane == 0 { continue }
+
+                    for row in 0 .. rows {
+                        if y + row >= height && !wrap { break }
+
+                        for column in 0 .. columns {
+                            if x + column >= width && !wrap { break }
+
+                            let pos = address!(start + row * stride + column / 8);
+                            let line = self.memory[pos];
+
+                            if line & (0x80 >> (column % 8)) != 0 &&
+                               self.screen.toggle((x + column) % width, (y + row) % height, plane) {
+                                register!(0xF) = 1
+                            }
+                        }
+                    }
+
+                    start += rows * stride
                 }
 
-                else if mode == 0x0A {
-                    not_implemented!()
+                let sprite = Region { x, y, width: columns, height: rows };
+
+                for region in sprite.on_screen(width, height, wrap) {
+                    self.touch(region)
                 }
 
-                else if mode == 0x15 {
-                    self.delay = op.x()
+                if self.quirks.display_wait {
+                    self.vblank = true
                 }
+            },
 
-                else if mode == 0x18 {
-                    self.sound = op.x()
+            // Skips the next instruction
+            // if the key in VX is pressed.
+            SkipKey { x } => {
+                if self.keys[(register!(x) & 0xF) as usize] {
+                    self.skip()
                 }
+            },
 
-                else if mode == 0x1E {
-                    self.index += register!(op.x()) as u16
+            // Skips the next instruction
+            // if the key in VX isn't pressed.
+            SkipNotKey { x } => {
+                if !self.keys[(register!(x) & 0xF) as usize] {
+                    self.skip()
+                }
+            },
+
+            LoadDelay { x } => {
+                register!(x) = self.delay
+            },
+
+            // Waits for a key to be pressed and released,
+            // then stores it in VX. Execution is halted by
+            // step() until release() completes the wait.
+            WaitKey { x } => {
+                self.waiting = Some(x)
+            },
+
+            SetDelay { x } => {
+                self.delay = register!(x)
+            },
+
+            SetSound { x } => {
+                self.sound = register!(x)
+            },
+
+            AddIndex { x } => {
+                self.index = self.index.wrapping_add(register!(x) as u16)
+            },
+
+            // Sets I to the 16 bit address following
+            // the opcode, and skips over it.
+            LongIndex => {
+                let pos = address!(self.counter + 1);
+                self.index = ((self.memory[pos - 1] as u16) << 8) | self.memory[pos] as u16;
+                self.counter += 2
+            },
+
+            // Selects the planes that are drawn on,
+            // scrolled and cleared.
+            SelectPlanes { x } => {
+                self.planes = x
+            },
+
+            // Loads the 16 byte audio pattern from I.
+            LoadPattern => {
+                let pos = address!(self.index as usize + 15) - 15;
+                self.pattern.copy_from_slice(&self.memory[pos .. pos + 16])
+            },
+
+            // Sets the pattern's playback rate from VX.
+            SetPitch { x } => {
+                self.pitch = register!(x)
+            },
+
+            // Sets I to the font glyph
+            // for the digit in VX.
+            LoadGlyph { x } => {
+                self.index = glyph_address(register!(x))
+            },
+
+            // Sets I to the large font glyph
+            // for the digit in VX.
+            LoadBigGlyph { x } => {
+                self.index = big_glyph_address(register!(x))
+            },
+
+            // Stores the decimal digits of VX
+            // at I, I + 1 and I + 2.
+            StoreBcd { x } => {
+                let vx = register!(x);
+                let pos = self.index as usize;
+                address!(pos + 2);
+                self.memory[pos] = vx / 100;
+                self.memory[pos + 1] = vx / 10 % 10;
+                self.memory[pos + 2] = vx % 10
+            },
+
+            Store { x } => {
+                for i in 0 .. (x + 1) {
+                    let pos = address!((self.index as usize) + i as usize);
+                    self.memory[pos] = register!(i)
                 }
 
-                else if mode == 0x29 {
-                    not_implemented!()
+                if self.quirks.increment_index {
+                    self.index = self.index.wrapping_add(x as u16 + 1)
                 }
+            },
 
-                else if mode == 0x33 {
-                    not_implemented!()
+            Restore { x } => {
+                for i in 0 .. (x + 1) {
+                    let pos = address!((self.index as usize) + i as usize);
+                    register!(i) = self.memory[pos]
                 }
 
-                else if mode == 0x55 {
-                    let register = op.x();                    
-                    
-                    for i in 0 .. (register + 1) {
-                        let pos = (self.index as usize) + i as usize;
-                        self.memory[pos] = register!(i)
-                    }
+                if self.quirks.increment_index {
+                    self.index = self.index.wrapping_add(x as u16 + 1)
                 }
+            },
 
-                else if mode == 0x65 {
-                    let register = op.x();
+            // Saves V0 to VX in the user flags, and
+            // persists them if there's a flag store.
+            // SUPER-CHIP only has flags for V0 to V7.
+            SaveFlags { x } => {
+                if x > 7 && self.platform < Platform::XoChip { invalid!() }
+                self.flags[.. (x as usize + 1)].copy_from_slice(&self.registers[.. (x as usize + 1)]);
 
-                    for i in 0 .. (register + 1) {
-                        let pos = (self.index as usize) + i as usize;
-                        register!(i) = self.memory[pos]
+                if let (Some(ref mut store), Some(hash)) = (self.flag_store.as_mut(), self.rom_hash) {
+                    if let Err(error) = store.save(hash, &self.flags) {
+                        self.flag_store_error = Some(error)
                     }
                 }
-
-                else { not_implemented!() }
             },
-            
-            _ => { not_implemented!() }
+
+            // Loads V0 to VX from the user flags.
+            LoadFlags { x } => {
+                if x > 7 && self.platform < Platform::XoChip { invalid!() }
+                self.registers[.. (x as usize + 1)].copy_from_slice(&self.flags[.. (x as usize + 1)])
+            }
         }
+
+        Ok(())
     }
 
-    /// Read a file into program memory.
+    /// Read a file into program memory. Octo cartridges
+    /// are compiled, and set the speed, quirks, platform
+    /// and colours they were made with first.
     pub fn load_file<P: AsRef<Path>>(&mut self, path: P) -> IOResult<()> {
         let mut program: Vec<u8> = vec![];
-        let mut file = try!(File::open(path));
+        let mut file = File::open(path)?;
+        file.read_to_end(&mut program)?;
 
+        if is_cartridge(&program) {
+            let cartridge = Cartridge::decode(&program)?;
+            cartridge.configure(self);
+            return self.load_rom(&cartridge.compile()?)
+        }
+
+        self.load_rom(&program)
+    }
+
+    /// Copy a program into memory, and restore
+    /// its flags from the flag store if it has one.
+    /// If the store can't be read, the program starts with
+    /// cleared flags, and the error is in flag_store_error.
+    /// Set the platform first, as XO-CHIP has more room.
+    pub fn load_rom(&mut self, program: &[u8]) -> IOResult<()> {
         // Return with an error if there's no space.
-        if try!(file.read_to_end(&mut program)) > (0x1000 - 200) {
-            Err(IOError::new(ErrorKind::Other, "ROM is too large!"))
+        if program.len() > (self.memory_size() - PROGRAM_ADDRESS) {
+            return Err(IOError::other("ROM is too large!"))
+        }
+
+        let region = &mut self.memory[PROGRAM_ADDRESS..(PROGRAM_ADDRESS + program.len())];
+        region.clone_from_slice(program);
+
+        let hash = rom_hash(program);
+        self.rom_hash = Some(hash);
+
+        if let Some(ref mut store) = self.flag_store {
+            match store.load(hash) {
+                Ok(Some(flags)) => self.flags = flags,
+                Ok(None) => {},
+                Err(error) => {
+                    self.flags = [0; FLAG_COUNT];
+                    self.flag_store_error = Some(error)
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Fetch and execute the instruction at the counter.
+    /// Nothing is executed while waiting for a key.
+    pub fn step(&mut self) -> Result<Status, CpuError> {
+        if self.timer_mode == TimerMode::WallClock {
+            self.sync_timers()
+        }
+
+        if self.exited {
+            return Ok(Status::Halted)
+        }
+
+        if self.waiting.is_some() {
+            return Ok(Status::WaitingForKey)
+        }
+
+        if self.vblank {
+            return Ok(Status::WaitingForFrame)
+        }
+
+        let pc = self.counter;
+
+        if pc + 1 >= self.memory_size() {
+            return Err(CpuError::CounterOverflow { pc, opcode: 0 })
+        }
+
+        let op = {
+            let p1 = (self.memory[pc] as u16) << 8;
+            let p2 = self.memory[pc + 1] as u16;
+            p1 + p2
+        };
+
+        // What the instruction costs depends on VX,
+        // which it may change, and on whether it skips.
+        let decoded = Instruction::decode(op);
+        let vx = self.registers[op.x() as usize];
+
+        self.counter += 2;
+        self.emulate(op)?;
+
+        if let Ok(instruction) = decoded {
+            let skipped = self.counter != pc + 2;
+            self.charge(vip_cycles(instruction, vx, skipped))
+        }
+
+        // Catch jumps and skips that leave memory
+        // here, rather than on the next fetch.
+        if self.counter + 1 >= self.memory_size() {
+            return Err(CpuError::CounterOverflow { pc, opcode: op })
+        }
+
+        if self.waiting.is_some() {
+            Ok(Status::WaitingForKey)
+        }
+
+        else if self.vblank {
+            Ok(Status::WaitingForFrame)
+        }
+
+        // An instruction that leaves the counter on
+        // itself will do so forever.
+        else if self.exited || self.counter == pc {
+            Ok(Status::Halted)
         }
 
         else {
-            let region = &mut self.memory[0x200..(0x200 + program.len())];
-            region.clone_from_slice(&program);
-            Ok(())
+            Ok(Status::Running)
+        }
+    }
+
+    /// Execute up to the given number of instructions,
+    /// stopping early if the program waits or halts.
+    pub fn run_cycles(&mut self, cycles: usize) -> Result<Status, CpuError> {
+        let mut status = Status::Running;
+
+        for _ in 0 .. cycles {
+            status = self.step()?;
+
+            if status != Status::Running {
+                break
+            }
+        }
+
+        Ok(status)
+    }
+
+    /// Poll for input, execute a frame's worth of
+    /// instructions, present the screen if it has
+    /// changed, then tick the timers once.
+    pub fn run_frame(&mut self) -> Result<Status, CpuError> {
+        self.poll_input();
+
+        let status = match self.timing {
+            Timing::Speed => {
+                let speed = self.speed;
+                self.run_cycles(speed)?
+            },
+
+            Timing::Vip => self.run_vip_frame()?
+        };
+
+        self.present();
+
+        if self.timer_mode == TimerMode::Frame {
+            self.tick_timers()
+        }
+
+        Ok(status)
+    }
+
+    /// Execute instructions until the machine cycles the VIP
+    /// has for the program each frame run out. If the program
+    /// waits, the rest of the frame is spent waiting.
+    fn run_vip_frame(&mut self) -> Result<Status, CpuError> {
+        self.cycles_left += VIP_PROGRAM_CYCLES as i64;
+        let mut status = Status::Running;
+
+        while self.cycles_left > 0 {
+            status = self.step()?;
+
+            if status != Status::Running {
+                break
+            }
+        }
+
+        self.cycles_left = self.cycles_left.min(0);
+        Ok(status)
+    }
+
+    /// Count the delay and sound timers down by one.
+    /// This is the display interrupt, so it also
+    /// ends any wait for it.
+    pub fn tick_timers(&mut self) {
+        self.vblank = false;
+        self.delay = self.delay.saturating_sub(1);
+        self.sound = self.sound.saturating_sub(1);
+    }
+
+    /// Tick the timers once for every 1/TIMER_RATE
+    /// seconds that have passed since they last ticked.
+    fn sync_timers(&mut self) {
+        let now = Instant::now();
+        let last = *self.last_tick.get_or_insert(now);
+        let period = 1_000_000_000 / TIMER_RATE as u128;
+        let ticks = (now - last).as_nanos() / period;
+
+        // Both timers are zero after 255 ticks,
+        // so there's no need to count further.
+        for _ in 0 .. ticks.min(255) {
+            self.tick_timers()
         }
+
+        self.last_tick = Some(last + Duration::from_nanos((ticks * period) as u64));
     }
 
-    /// Run the program contained in memory.
-    /// This function will never return.
-    pub fn run(&mut self) -> ! {        
+    /// Run the program contained in memory at FRAME_RATE
+    /// frames per second, until it halts or faults.
+    pub fn run(&mut self) -> Result<(), CpuError> {
+        let frame = Duration::new(0, 1_000_000_000 / FRAME_RATE);
+        let mut next = Instant::now();
+
         loop {
-            let op = {
-                let p1 = (self.memory[self.counter] as u16) << 8;
-                let p2 = self.memory[self.counter + 1] as u16;
-                p1 + p2
-            };
-            
-            self.emulate(op);
-            self.counter += 2;
+            if self.run_frame()? == Status::Halted {
+                return Ok(())
+            }
+
+            // Sleep off the rest of the frame. If we've
+            // fallen behind, don't try to catch up.
+            next += frame;
+            let now = Instant::now();
+
+            if next > now {
+                thread::sleep(next - now)
+            }
+
+            else {
+                next = now
+            }
         }
     }
 }
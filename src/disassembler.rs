@@ -0,0 +1,539 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use cpu::{Opcode, PROGRAM_ADDRESS};
+use instruction::Instruction;
+
+/// The assembly language a listing is written in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Syntax {
+    /// Cowgod's Chip-8 Technical Reference, as in
+    /// `LD V0, #05`, with the SUPER-CHIP mnemonics from
+    /// the same document and a few more for XO-CHIP.
+    Cowgod,
+    /// Octo, as in `v0 := 0x05`.
+    Octo
+}
+
+/// One line of a listing: an instruction, or data
+/// that doesn't decode as one.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Line {
+    // Where the line is loaded in memory.
+    pub address: usize,
+    // The bytes disassembled, two for most instructions.
+    pub bytes: Vec<u8>,
+    // The instruction, or None for data.
+    pub instruction: Option<Instruction>,
+    // The assembly text, in the listing's syntax.
+    pub text: String
+}
+
+impl Syntax {
+    /// Look up a syntax by its name.
+    pub fn from_name(name: &str) -> Option<Syntax> {
+        match name {
+            "cowgod" => Some(Syntax::Cowgod),
+            "octo" => Some(Syntax::Octo),
+            _ => None
+        }
+    }
+}
+
+/// Disassemble a ROM from start to end, as loaded at
+/// PROGRAM_ADDRESS. Every two bytes are read as an instruction,
+/// or four for F000 NNNN, so data mixed in with code will be
+/// shown as instructions if it happens to decode.
+pub fn disassemble(rom: &[u8], syntax: Syntax) -> Vec<Line> {
+    let mut lines = vec![];
+    let mut offset = 0;
+
+    while offset < rom.len() {
+        let address = PROGRAM_ADDRESS + offset;
+
+        if offset + 1 == rom.len() {
+            lines.push(data(address, &rom[offset ..], syntax));
+            break
+        }
+
+        let op = ((rom[offset] as Opcode) << 8) | rom[offset + 1] as Opcode;
+
+        let line = match Instruction::decode(op) {
+            // Long I without its address is just data.
+            Ok(Instruction::LongIndex) if offset + 4 > rom.len() =>
+                data(address, &rom[offset .. offset + 2], syntax),
+
+            Ok(instruction) => {
+                let bytes = rom[offset .. offset + instruction.size()].to_vec();
+                let long = bytes.get(2 .. 4).map(|word| ((word[0] as u16) << 8) | word[1] as u16);
+
+                Line {
+                    address,
+                    text: format(instruction, long.unwrap_or(0), syntax),
+                    instruction: Some(instruction),
+                    bytes
+                }
+            },
+
+            Err(_) => data(address, &rom[offset .. offset + 2], syntax)
+        };
+
+        offset += line.bytes.len();
+        lines.push(line);
+    }
+
+    lines
+}
+
+/// How the program refers to an address. When it's
+/// referred to in more than one way, the last is named for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Reference {
+    // ANNN or F000 NNNN.
+    Data,
+    // 1NNN or BNNN.
+    Jump,
+    // 2NNN.
+    Call
+}
+
+/// What following the control flow of a ROM found.
+struct Trace {
+    // Set for every byte of every instruction reached.
+    code: Vec<bool>,
+    // Set for the first byte of every instruction reached.
+    starts: Vec<bool>,
+    // Addresses the program refers to, and how.
+    references: BTreeMap<usize, Reference>
+}
+
+/// The opcode at an offset into a ROM, if there's room for one.
+fn opcode(rom: &[u8], offset: usize) -> Option<Opcode> {
+    if offset + 1 < rom.len() {
+        Some(((rom[offset] as Opcode) << 8) | rom[offset + 1] as Opcode)
+    } else {
+        None
+    }
+}
+
+/// Follow every path through a ROM from PROGRAM_ADDRESS,
+/// through jumps, calls and both sides of skips. BNNN is
+/// followed as if V0 is zero, the start of its jump table.
+/// Paths stop at anything that doesn't decode, leaves the
+/// ROM, or would overlap an instruction already found.
+fn trace(rom: &[u8]) -> Trace {
+    use instruction::Instruction::*;
+
+    let mut trace = Trace {
+        code: vec![false; rom.len()],
+        starts: vec![false; rom.len()],
+        references: BTreeMap::new()
+    };
+
+    let mut pending = vec![PROGRAM_ADDRESS];
+
+    while let Some(address) = pending.pop() {
+        let offset = match address.checked_sub(PROGRAM_ADDRESS) {
+            Some(offset) if offset < rom.len() => offset,
+            _ => continue
+        };
+
+        if trace.starts[offset] { continue }
+
+        let instruction = match opcode(rom, offset).map(Instruction::decode) {
+            Some(Ok(instruction)) => instruction,
+            _ => continue
+        };
+
+        let size = instruction.size();
+
+        if offset + size > rom.len() || trace.code[offset .. offset + size].contains(&true) {
+            continue
+        }
+
+        for byte in &mut trace.code[offset .. offset + size] {
+            *byte = true
+        }
+
+        trace.starts[offset] = true;
+
+        let next = address + size;
+        let mut refer = |address: u16, reference: Reference| {
+            let known = trace.references.entry(address as usize).or_insert(reference);
+            *known = (*known).max(reference)
+        };
+
+        match instruction {
+            Jump { nnn } | JumpOffset { nnn } => {
+                refer(nnn, Reference::Jump);
+                pending.push(nnn as usize)
+            },
+
+            Call { nnn } => {
+                refer(nnn, Reference::Call);
+                pending.push(nnn as usize);
+                pending.push(next)
+            },
+
+            Return | Exit => {},
+
+            // Skipping over F000 NNNN skips four bytes.
+            SkipEqImm { .. } | SkipNeImm { .. } | SkipEq { .. } |
+            SkipNe { .. } | SkipKey { .. } | SkipNotKey { .. } => {
+                let long = opcode(rom, next - PROGRAM_ADDRESS) == Some(0xF000);
+                pending.push(next);
+                pending.push(next + if long { 4 } else { 2 })
+            },
+
+            LoadIndex { nnn } => {
+                refer(nnn, Reference::Data);
+                pending.push(next)
+            },
+
+            LongIndex => {
+                let long = ((rom[offset + 2] as u16) << 8) | rom[offset + 3] as u16;
+                refer(long, Reference::Data);
+                pending.push(next)
+            },
+
+            _ => pending.push(next)
+        }
+    }
+
+    trace
+}
+
+/// Disassemble a ROM into source that assembles back into
+/// the same bytes: with the assembler for Cowgod's syntax,
+/// or the Octo compiler for Octo's. Code is found by following
+/// the control flow with trace(), and everything else is
+/// written as bytes. Addresses in the ROM that the program
+/// refers to are given labels, unless they're in the
+/// middle of an instruction.
+pub fn disassemble_source(rom: &[u8], syntax: Syntax) -> String {
+    let trace = trace(rom);
+
+    let labels: BTreeMap<usize, String> = trace.references.iter()
+        .filter(|&(&address, _)| {
+            let offset = address.wrapping_sub(PROGRAM_ADDRESS);
+            offset < rom.len() && (!trace.code[offset] || trace.starts[offset])
+        })
+        .map(|(&address, &reference)| {
+            let prefix = match reference {
+                Reference::Data => "data",
+                Reference::Jump => "label",
+                Reference::Call => "sub"
+            };
+
+            (address, format!("{}_{:03x}", prefix, address))
+        })
+        .collect();
+
+    // Octo programs start at main.
+    let mut source = match syntax {
+        Syntax::Cowgod => String::new(),
+        Syntax::Octo => ": main\n".to_string()
+    };
+
+    let mut offset = 0;
+
+    while offset < rom.len() {
+        let address = PROGRAM_ADDRESS + offset;
+
+        if let Some(label) = labels.get(&address) {
+            source.push_str(&match syntax {
+                Syntax::Cowgod => format!("{}:\n", label),
+                Syntax::Octo => format!(": {}\n", label)
+            })
+        }
+
+        if trace.starts[offset] {
+            let op = opcode(rom, offset).unwrap_or(0);
+            let instruction = Instruction::decode(op).expect("traced instructions decode");
+            let long = opcode(rom, offset + 2).unwrap_or(0);
+
+            source.push_str("    ");
+            source.push_str(&format_with_labels(instruction, long, syntax, &labels));
+            source.push('\n');
+            offset += instruction.size();
+            continue
+        }
+
+        // Bytes run up to the next instruction or
+        // label, with at most 8 on a line.
+        let mut end = offset + 1;
+
+        while end < rom.len() && end - offset < 8 &&
+              !trace.starts[end] && !labels.contains_key(&(PROGRAM_ADDRESS + end)) {
+            end += 1
+        }
+
+        let bytes = &rom[offset .. end];
+
+        source.push_str("    ");
+        source.push_str(&match syntax {
+            Syntax::Cowgod => format!("byte {}", bytes.iter()
+                .map(|byte| format!("#{:02X}", byte)).collect::<Vec<_>>().join(", ")),
+            Syntax::Octo => bytes.iter()
+                .map(|byte| format!("0x{:02X}", byte)).collect::<Vec<_>>().join(" ")
+        });
+        source.push('\n');
+        offset = end
+    }
+
+    source
+}
+
+/// A line of raw bytes.
+fn data(address: usize, bytes: &[u8], syntax: Syntax) -> Line {
+    let text = match syntax {
+        Syntax::Cowgod if bytes.len() == 2 =>
+            format!("DW #{:02X}{:02X}", bytes[0], bytes[1]),
+        Syntax::Cowgod =>
+            format!("DB #{:02X}", bytes[0]),
+        Syntax::Octo =>
+            bytes.iter().map(|byte| format!("0x{:02X}", byte)).collect::<Vec<_>>().join(" ")
+    };
+
+    Line { address, bytes: bytes.to_vec(), instruction: None, text }
+}
+
+/// The assembly text for an instruction. Long is the
+/// address following F000, and is ignored otherwise.
+pub fn format(instruction: Instruction, long: u16, syntax: Syntax) -> String {
+    format_with_labels(instruction, long, syntax, &BTreeMap::new())
+}
+
+/// As format(), but addresses with a label are written by name.
+fn format_with_labels(instruction: Instruction, long: u16, syntax: Syntax,
+                      labels: &BTreeMap<usize, String>) -> String {
+    match syntax {
+        Syntax::Cowgod => cowgod(instruction, long, labels),
+        Syntax::Octo => octo(instruction, long, labels)
+    }
+}
+
+fn cowgod(instruction: Instruction, long: u16, labels: &BTreeMap<usize, String>) -> String {
+    use instruction::Instruction::*;
+
+    let address = |address: u16, digits: usize| labels.get(&(address as usize)).cloned()
+        .unwrap_or_else(|| format!("#{:01$X}", address, digits));
+
+    match instruction {
+        Sys { nnn } => format!("SYS #{:03X}", nnn),
+        Clear => "CLS".to_string(),
+        Return => "RET".to_string(),
+        ScrollDown { n } => format!("SCD {}", n),
+        ScrollUp { n } => format!("SCU {}", n),
+        ScrollRight => "SCR".to_string(),
+        ScrollLeft => "SCL".to_string(),
+        Exit => "EXIT".to_string(),
+        LowRes => "LOW".to_string(),
+        HighRes => "HIGH".to_string(),
+        Jump { nnn } => format!("JP {}", address(nnn, 3)),
+        Call { nnn } => format!("CALL {}", address(nnn, 3)),
+        SkipEqImm { x, nn } => format!("SE V{:X}, #{:02X}", x, nn),
+        SkipNeImm { x, nn } => format!("SNE V{:X}, #{:02X}", x, nn),
+        SkipEq { x, y } => format!("SE V{:X}, V{:X}", x, y),
+        SaveRange { x, y } => format!("SAVE V{:X}, V{:X}", x, y),
+        LoadRange { x, y } => format!("LOAD V{:X}, V{:X}", x, y),
+        LoadImm { x, nn } => format!("LD V{:X}, #{:02X}", x, nn),
+        AddImm { x, nn } => format!("ADD V{:X}, #{:02X}", x, nn),
+        Load { x, y } => format!("LD V{:X}, V{:X}", x, y),
+        Or { x, y } => format!("OR V{:X}, V{:X}", x, y),
+        And { x, y } => format!("AND V{:X}, V{:X}", x, y),
+        Xor { x, y } => format!("XOR V{:X}, V{:X}", x, y),
+        Add { x, y } => format!("ADD V{:X}, V{:X}", x, y),
+        Sub { x, y } => format!("SUB V{:X}, V{:X}", x, y),
+        ShiftRight { x, y } => format!("SHR V{:X}, V{:X}", x, y),
+        SubReverse { x, y } => format!("SUBN V{:X}, V{:X}", x, y),
+        ShiftLeft { x, y } => format!("SHL V{:X}, V{:X}", x, y),
+        SkipNe { x, y } => format!("SNE V{:X}, V{:X}", x, y),
+        LoadIndex { nnn } => format!("LD I, {}", address(nnn, 3)),
+        JumpOffset { nnn } => format!("JP V0, {}", address(nnn, 3)),
+        Random { x, nn } => format!("RND V{:X}, #{:02X}", x, nn),
+        Draw { x, y, n } => format!("DRW V{:X}, V{:X}, {}", x, y, n),
+        SkipKey { x } => format!("SKP V{:X}", x),
+        SkipNotKey { x } => format!("SKNP V{:X}", x),
+        LongIndex => format!("LD I, LONG {}", address(long, 4)),
+        SelectPlanes { x } => format!("PLANE {}", x),
+        LoadPattern => "AUDIO".to_string(),
+        LoadDelay { x } => format!("LD V{:X}, DT", x),
+        WaitKey { x } => format!("LD V{:X}, K", x),
+        SetDelay { x } => format!("LD DT, V{:X}", x),
+        SetSound { x } => format!("LD ST, V{:X}", x),
+        AddIndex { x } => format!("ADD I, V{:X}", x),
+        LoadGlyph { x } => format!("LD F, V{:X}", x),
+        LoadBigGlyph { x } => format!("LD HF, V{:X}", x),
+        SetPitch { x } => format!("LD PITCH, V{:X}", x),
+        StoreBcd { x } => format!("LD B, V{:X}", x),
+        Store { x } => format!("LD [I], V{:X}", x),
+        Restore { x } => format!("LD V{:X}, [I]", x),
+        SaveFlags { x } => format!("LD R, V{:X}", x),
+        LoadFlags { x } => format!("LD V{:X}, R", x)
+    }
+}
+
+fn octo(instruction: Instruction, long: u16, labels: &BTreeMap<usize, String>) -> String {
+    use instruction::Instruction::*;
+
+    let address = |address: u16, digits: usize| labels.get(&(address as usize)).cloned()
+        .unwrap_or_else(|| format!("0x{:01$X}", address, digits));
+
+    match instruction {
+        // Octo can't call machine code, so it's written as bytes.
+        Sys { nnn } => format!("0x{:02X} 0x{:02X}", nnn >> 8, nnn & 0xFF),
+        Clear => "clear".to_string(),
+        Return => "return".to_string(),
+        ScrollDown { n } => format!("scroll-down {}", n),
+        ScrollUp { n } => format!("scroll-up {}", n),
+        ScrollRight => "scroll-right".to_string(),
+        ScrollLeft => "scroll-left".to_string(),
+        Exit => "exit".to_string(),
+        LowRes => "lores".to_string(),
+        HighRes => "hires".to_string(),
+        Jump { nnn } => format!("jump {}", address(nnn, 3)),
+        Call { nnn } => format!(":call {}", address(nnn, 3)),
+
+        // Octo's conditions say when the next
+        // instruction runs, not when it's skipped.
+        SkipEqImm { x, nn } => format!("if v{:x} != 0x{:02X} then", x, nn),
+        SkipNeImm { x, nn } => format!("if v{:x} == 0x{:02X} then", x, nn),
+        SkipEq { x, y } => format!("if v{:x} != v{:x} then", x, y),
+        SkipNe { x, y } => format!("if v{:x} == v{:x} then", x, y),
+        SkipKey { x } => format!("if v{:x} -key then", x),
+        SkipNotKey { x } => format!("if v{:x} key then", x),
+
+        SaveRange { x, y } => format!("save v{:x} - v{:x}", x, y),
+        LoadRange { x, y } => format!("load v{:x} - v{:x}", x, y),
+        LoadImm { x, nn } => format!("v{:x} := 0x{:02X}", x, nn),
+        AddImm { x, nn } => format!("v{:x} += 0x{:02X}", x, nn),
+        Load { x, y } => format!("v{:x} := v{:x}", x, y),
+        Or { x, y } => format!("v{:x} |= v{:x}", x, y),
+        And { x, y } => format!("v{:x} &= v{:x}", x, y),
+        Xor { x, y } => format!("v{:x} ^= v{:x}", x, y),
+        Add { x, y } => format!("v{:x} += v{:x}", x, y),
+        Sub { x, y } => format!("v{:x} -= v{:x}", x, y),
+        ShiftRight { x, y } => format!("v{:x} >>= v{:x}", x, y),
+        SubReverse { x, y } => format!("v{:x} =- v{:x}", x, y),
+        ShiftLeft { x, y } => format!("v{:x} <<= v{:x}", x, y),
+        LoadIndex { nnn } => format!("i := {}", address(nnn, 3)),
+        JumpOffset { nnn } => format!("jump0 {}", address(nnn, 3)),
+        Random { x, nn } => format!("v{:x} := random 0x{:02X}", x, nn),
+        Draw { x, y, n } => format!("sprite v{:x} v{:x} {}", x, y, n),
+        LongIndex => format!("i := long {}", address(long, 4)),
+        SelectPlanes { x } => format!("plane {}", x),
+        LoadPattern => "audio".to_string(),
+        LoadDelay { x } => format!("v{:x} := delay", x),
+        WaitKey { x } => format!("v{:x} := key", x),
+        SetDelay { x } => format!("delay := v{:x}", x),
+        SetSound { x } => format!("buzzer := v{:x}", x),
+        AddIndex { x } => format!("i += v{:x}", x),
+        LoadGlyph { x } => format!("i := hex v{:x}", x),
+        LoadBigGlyph { x } => format!("i := bighex v{:x}", x),
+        SetPitch { x } => format!("pitch := v{:x}", x),
+        StoreBcd { x } => format!("bcd v{:x}", x),
+        Store { x } => format!("save v{:x}", x),
+        Restore { x } => format!("load v{:x}", x),
+        SaveFlags { x } => format!("saveflags v{:x}", x),
+        LoadFlags { x } => format!("loadflags v{:x}", x)
+    }
+}
+
+impl fmt::Display for Line {
+    /// The address and bytes in hex, then the text.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let bytes: String = self.bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+        write!(f, "{:04X}  {:<8}  {}", self.address, bytes, self.text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A call, a jump to itself, and data after the code:
+    /// two bytes that decode as SE and one left over.
+    const ROM: [u8; 13] = [
+        0x00, 0xE0, 0xA2, 0x0A, 0x22, 0x08, 0x12, 0x06,
+        0x00, 0xEE, 0x3C, 0x42, 0x81
+    ];
+
+    fn listing(syntax: Syntax) -> Vec<String> {
+        disassemble(&ROM, syntax).iter().map(Line::to_string).collect()
+    }
+
+    #[test]
+    fn lists_cowgod() {
+        assert_eq!(listing(Syntax::Cowgod), [
+            "0200  00E0      CLS",
+            "0202  A20A      LD I, #20A",
+            "0204  2208      CALL #208",
+            "0206  1206      JP #206",
+            "0208  00EE      RET",
+            "020A  3C42      SE VC, #42",
+            "020C  81        DB #81"
+        ]);
+    }
+
+    #[test]
+    fn lists_octo() {
+        assert_eq!(listing(Syntax::Octo), [
+            "0200  00E0      clear",
+            "0202  A20A      i := 0x20A",
+            "0204  2208      :call 0x208",
+            "0206  1206      jump 0x206",
+            "0208  00EE      return",
+            "020A  3C42      if vc != 0x42 then",
+            "020C  81        0x81"
+        ]);
+    }
+
+    /// A skip with code on both paths, then a CLS that
+    /// is never reached, before the loop both jump to.
+    const FLOW: [u8; 12] = [
+        0x30, 0x00, 0x12, 0x0A, 0x60, 0x01, 0x12, 0x0A,
+        0x00, 0xE0, 0x12, 0x0A
+    ];
+
+    #[test]
+    fn follows_flow_in_cowgod() {
+        assert_eq!(disassemble_source(&FLOW, Syntax::Cowgod), "    SE V0, #00
+    JP label_20a
+    LD V0, #01
+    JP label_20a
+    byte #00, #E0
+label_20a:
+    JP label_20a
+");
+    }
+
+    #[test]
+    fn follows_flow_in_octo() {
+        assert_eq!(disassemble_source(&FLOW, Syntax::Octo), "\
+: main
+    if v0 != 0x00 then
+    jump label_20a
+    v0 := 0x01
+    jump label_20a
+    0x00 0xE0
+: label_20a
+    jump label_20a
+");
+    }
+
+    #[test]
+    fn labels_calls_and_data() {
+        let source = disassemble_source(&ROM, Syntax::Cowgod);
+        assert!(source.contains("LD I, data_20a\n"));
+        assert!(source.contains("CALL sub_208\nlabel_206:\n"));
+        assert!(source.ends_with("data_20a:\n    byte #3C, #42, #81\n"));
+    }
+
+    #[test]
+    fn reads_long_index_as_one_line() {
+        let lines = disassemble(&[0xF0, 0x00, 0x12, 0x34, 0xF0, 0x02], Syntax::Cowgod);
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0].bytes, [0xF0, 0x00, 0x12, 0x34]);
+        assert_eq!(lines[1].address, 0x204);
+    }
+}
@@ -0,0 +1,234 @@
+/// Width of the screen in pixels.
+pub const WIDTH: usize = 64;
+
+/// Height of the screen in pixels.
+pub const HEIGHT: usize = 32;
+
+/// Width of the SUPER-CHIP high resolution screen.
+pub const HIRES_WIDTH: usize = 128;
+
+/// Height of the SUPER-CHIP high resolution screen.
+pub const HIRES_HEIGHT: usize = 64;
+
+/// Number of bitplanes. CHIP-8 and SUPER-CHIP only
+/// draw on the first, XO-CHIP programs select planes
+/// with FN01.
+pub const PLANES: usize = 4;
+
+/// The screen, owned by the interpreter.
+/// It is WIDTH by HEIGHT pixels, or HIRES_WIDTH by
+/// HIRES_HEIGHT in high resolution mode. Each pixel
+/// has a bit for each plane, so with one plane in use
+/// a pixel is either 0 or 1.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Framebuffer {
+    // Rows of pixels, one after another.
+    pixels: Vec<u8>,
+    width: usize,
+    height: usize
+}
+
+/// The colour of each pixel value, so the
+/// colour of a pixel on planes 1 and 2 is at 3.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Palette(pub [[u8; 3]; 1 << PLANES]);
+
+/// A rectangle of the screen that has changed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Region {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize
+}
+
+impl Region {
+    /// The parts of this region that fall on a screen of the
+    /// given size. Anything past the right or bottom edge is
+    /// dropped, or wrapped around to the other side with wrap.
+    pub fn on_screen(&self, width: usize, height: usize, wrap: bool) -> Vec<Region> {
+        // Splits a span into the part before the edge
+        // and the part that wraps past it.
+        fn spans(start: usize, length: usize, size: usize, wrap: bool) -> [(usize, usize); 2] {
+            let end = start + length;
+            let over = if wrap { end.saturating_sub(size).min(start) } else { 0 };
+            [(start, end.min(size)), (0, over)]
+        }
+
+        let mut regions = vec![];
+
+        for &(left, right) in &spans(self.x, self.width, width, wrap) {
+            for &(top, bottom) in &spans(self.y, self.height, height, wrap) {
+                if left < right && top < bottom {
+                    regions.push(Region {
+                        x: left,
+                        y: top,
+                        width: right - left,
+                        height: bottom - top
+                    })
+                }
+            }
+        }
+
+        regions
+    }
+}
+
+impl Palette {
+    /// Two colours, for programs that only use plane 1.
+    /// Anything on the other planes is drawn as on.
+    pub fn monochrome(on: [u8; 3], off: [u8; 3]) -> Palette {
+        let mut colours = [on; 1 << PLANES];
+        colours[0] = off;
+        Palette(colours)
+    }
+
+    /// The colour of a pixel value.
+    pub fn colour(&self, pixel: u8) -> [u8; 3] {
+        self.0[pixel as usize % (1 << PLANES)]
+    }
+}
+
+impl Default for Palette {
+    /// White on black, with greys where planes 1 and 2
+    /// are used, and bright colours beyond that.
+    fn default() -> Palette {
+        Palette([
+            [0x00, 0x00, 0x00], [0xFF, 0xFF, 0xFF], [0xAA, 0xAA, 0xAA], [0x55, 0x55, 0x55],
+            [0xFF, 0x00, 0x00], [0x00, 0xFF, 0x00], [0x00, 0x00, 0xFF], [0xFF, 0xFF, 0x00],
+            [0x88, 0x00, 0x00], [0x00, 0x88, 0x00], [0x00, 0x00, 0x88], [0x88, 0x88, 0x00],
+            [0xFF, 0x00, 0xFF], [0x00, 0xFF, 0xFF], [0x88, 0x00, 0x88], [0x00, 0x88, 0x88]
+        ])
+    }
+}
+
+impl Framebuffer {
+    pub fn new() -> Framebuffer {
+        Framebuffer {
+            pixels: vec![0; WIDTH * HEIGHT],
+            width: WIDTH,
+            height: HEIGHT
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Whether the screen is in high resolution mode.
+    pub fn hires(&self) -> bool {
+        self.width == HIRES_WIDTH
+    }
+
+    /// Switch between low and high resolution.
+    /// The screen is cleared when it changes size.
+    pub fn set_hires(&mut self, hires: bool) {
+        let (width, height) = if hires {
+            (HIRES_WIDTH, HIRES_HEIGHT)
+        } else {
+            (WIDTH, HEIGHT)
+        };
+
+        self.pixels = vec![0; width * height];
+        self.width = width;
+        self.height = height
+    }
+
+    /// The region covering the whole screen.
+    pub fn bounds(&self) -> Region {
+        Region { x: 0, y: 0, width: self.width, height: self.height }
+    }
+
+    /// Whether the pixel at (x, y) is on in any plane.
+    /// Out of range pixels are always off.
+    pub fn get(&self, x: usize, y: usize) -> bool {
+        self.pixel(x, y) != 0
+    }
+
+    /// The planes that the pixel at (x, y) is on in,
+    /// with a bit for each. Out of range pixels are 0.
+    pub fn pixel(&self, x: usize, y: usize) -> u8 {
+        if x < self.width && y < self.height {
+            self.pixels[y * self.width + x]
+        } else {
+            0
+        }
+    }
+
+    /// Flip the pixel at (x, y) in a single plane, given
+    /// as its bit. Returns true if it was on and is now off.
+    pub fn toggle(&mut self, x: usize, y: usize, plane: u8) -> bool {
+        let pixel = &mut self.pixels[y * self.width + x];
+        *pixel ^= plane;
+        *pixel & plane == 0
+    }
+
+    /// Turn every pixel off, in every plane.
+    pub fn clear(&mut self) {
+        self.clear_planes(0xFF)
+    }
+
+    /// Turn every pixel off in the given planes.
+    pub fn clear_planes(&mut self, planes: u8) {
+        for pixel in self.pixels.iter_mut() {
+            *pixel &= !planes
+        }
+    }
+
+    /// A row of pixels, counting from the top.
+    pub fn row(&self, y: usize) -> &[u8] {
+        &self.pixels[y * self.width .. (y + 1) * self.width]
+    }
+
+    /// Move the given planes by (dx, dy) pixels.
+    /// Pixels moved off the edge are lost, and
+    /// the other planes are left where they are.
+    fn shift(&mut self, dx: isize, dy: isize, planes: u8) {
+        let old = self.pixels.clone();
+        let (width, height) = (self.width as isize, self.height as isize);
+
+        for y in 0 .. height {
+            for x in 0 .. width {
+                let (from_x, from_y) = (x - dx, y - dy);
+                let moved = if from_x >= 0 && from_x < width && from_y >= 0 && from_y < height {
+                    old[(from_y * width + from_x) as usize] & planes
+                } else {
+                    0
+                };
+
+                let pixel = &mut self.pixels[(y * width + x) as usize];
+                *pixel = (*pixel & !planes) | moved
+            }
+        }
+    }
+
+    /// Move the given planes down by n pixels.
+    pub fn scroll_down(&mut self, n: usize, planes: u8) {
+        self.shift(0, n.min(self.height) as isize, planes)
+    }
+
+    /// Move the given planes up by n pixels.
+    pub fn scroll_up(&mut self, n: usize, planes: u8) {
+        self.shift(0, -(n.min(self.height) as isize), planes)
+    }
+
+    /// Move the given planes right by n pixels.
+    pub fn scroll_right(&mut self, n: usize, planes: u8) {
+        self.shift(n.min(self.width) as isize, 0, planes)
+    }
+
+    /// Move the given planes left by n pixels.
+    pub fn scroll_left(&mut self, n: usize, planes: u8) {
+        self.shift(-(n.min(self.width) as isize), 0, planes)
+    }
+}
+
+impl Default for Framebuffer {
+    fn default() -> Framebuffer {
+        Framebuffer::new()
+    }
+}
@@ -0,0 +1,84 @@
+use std::env;
+use std::fs::{self, File};
+use std::io::{ErrorKind, Read, Result as IOResult, Write};
+use std::path::PathBuf;
+
+/// Number of RPL user flags. SUPER-CHIP uses
+/// the first 8, XO-CHIP all 16.
+pub const FLAG_COUNT: usize = 16;
+
+/// Somewhere to keep the RPL user flags between
+/// sessions, so games can save high scores.
+/// Flags are kept per ROM, identified by rom_hash().
+pub trait FlagStore {
+    /// Fetch the flags saved for a ROM, if there are any.
+    fn load(&mut self, rom: u64) -> IOResult<Option<[u8; FLAG_COUNT]>>;
+
+    /// Save the flags for a ROM.
+    fn save(&mut self, rom: u64, flags: &[u8; FLAG_COUNT]) -> IOResult<()>;
+}
+
+/// Keeps flags in a directory, in one file per ROM.
+pub struct FileFlagStore {
+    dir: PathBuf
+}
+
+/// Identify a ROM by a 64 bit FNV-1a hash of its contents.
+/// This is stable across builds, unlike std's hashers.
+pub fn rom_hash(rom: &[u8]) -> u64 {
+    let mut hash: u64 = 0xCBF2_9CE4_8422_2325;
+
+    for &byte in rom {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01B3);
+    }
+
+    hash
+}
+
+impl FileFlagStore {
+    /// Use the given directory, which is created
+    /// when flags are first saved.
+    pub fn new<P: Into<PathBuf>>(dir: P) -> FileFlagStore {
+        FileFlagStore { dir: dir.into() }
+    }
+
+    /// The usual place for flags: chip-butty/flags under
+    /// $XDG_DATA_HOME, or ~/.local/share without it.
+    pub fn default_dir() -> Option<PathBuf> {
+        let data = env::var_os("XDG_DATA_HOME")
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/share")))?;
+
+        Some(data.join("chip-butty").join("flags"))
+    }
+
+    fn path(&self, rom: u64) -> PathBuf {
+        self.dir.join(format!("{:016x}", rom))
+    }
+}
+
+impl FlagStore for FileFlagStore {
+    fn load(&mut self, rom: u64) -> IOResult<Option<[u8; FLAG_COUNT]>> {
+        let mut file = match File::open(self.path(rom)) {
+            Ok(file) => file,
+            Err(ref error) if error.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(error)
+        };
+
+        // Files saved with fewer flags leave the rest zeroed.
+        let mut contents = vec![];
+        file.read_to_end(&mut contents)?;
+
+        let mut flags = [0; FLAG_COUNT];
+        let count = contents.len().min(FLAG_COUNT);
+        flags[.. count].copy_from_slice(&contents[.. count]);
+        Ok(Some(flags))
+    }
+
+    fn save(&mut self, rom: u64, flags: &[u8; FLAG_COUNT]) -> IOResult<()> {
+        fs::create_dir_all(&self.dir)?;
+        let mut file = File::create(self.path(rom))?;
+        file.write_all(flags)
+    }
+}
@@ -0,0 +1,180 @@
+extern crate png;
+
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::{BufWriter, Error as IOError, Result as IOResult, Write};
+use std::path::Path;
+use std::rc::Rc;
+use cpu::Render;
+use display::{Framebuffer, Palette};
+
+/// A renderer that keeps presented frames in memory,
+/// for running ROMs where there is no display.
+/// Clones share the same frames, so keep one and hand
+/// another to the interpreter.
+#[derive(Clone)]
+pub struct HeadlessRenderer {
+    frames: Rc<RefCell<Vec<Framebuffer>>>,
+    // Only this many of the most recent frames are kept.
+    limit: usize
+}
+
+impl Default for HeadlessRenderer {
+    fn default() -> HeadlessRenderer {
+        HeadlessRenderer::new()
+    }
+}
+
+impl HeadlessRenderer {
+    /// Keep only the last frame presented, so a long
+    /// run doesn't fill memory with old frames.
+    pub fn new() -> HeadlessRenderer {
+        HeadlessRenderer::with_limit(1)
+    }
+
+    /// Keep up to the given number of frames,
+    /// dropping the oldest first.
+    pub fn with_limit(limit: usize) -> HeadlessRenderer {
+        HeadlessRenderer {
+            frames: Rc::default(),
+            limit
+        }
+    }
+
+    /// The frames kept so far, oldest first.
+    pub fn frames(&self) -> Vec<Framebuffer> {
+        self.frames.borrow().clone()
+    }
+
+    /// The most recently presented frame.
+    pub fn last(&self) -> Option<Framebuffer> {
+        self.frames.borrow().last().cloned()
+    }
+
+    /// Forget every frame presented so far.
+    pub fn clear(&self) {
+        self.frames.borrow_mut().clear()
+    }
+}
+
+impl Render for HeadlessRenderer {
+    fn present(&mut self, frame: &Framebuffer) {
+        let mut frames = self.frames.borrow_mut();
+        frames.push(frame.clone());
+
+        if frames.len() > self.limit {
+            let excess = frames.len() - self.limit;
+            frames.drain(.. excess);
+        }
+    }
+}
+
+/// How a framebuffer is drawn into an image.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PngOptions {
+    // Size of each pixel, in image pixels.
+    pub scale: u32,
+    // RGB colour of each pixel value.
+    pub palette: Palette
+}
+
+impl Default for PngOptions {
+    fn default() -> PngOptions {
+        PngOptions {
+            scale: 1,
+            palette: Palette::default()
+        }
+    }
+}
+
+/// Encode a framebuffer as a PNG image.
+pub fn write_png<W: Write>(frame: &Framebuffer, options: &PngOptions, out: W) -> IOResult<()> {
+    let scale = options.scale.max(1) as usize;
+    let (width, height) = (frame.width() * scale, frame.height() * scale);
+    let mut data = Vec::with_capacity(width * height * 3);
+
+    for y in 0 .. height {
+        for x in 0 .. width {
+            let colour = options.palette.colour(frame.pixel(x / scale, y / scale));
+            data.extend_from_slice(&colour);
+        }
+    }
+
+    let mut encoder = png::Encoder::new(out, width as u32, height as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    let mut writer = encoder.write_header().map_err(IOError::other)?;
+    writer.write_image_data(&data).map_err(IOError::other)?;
+    writer.finish().map_err(IOError::other)
+}
+
+/// Save a framebuffer to a PNG file.
+pub fn save_png<P: AsRef<Path>>(frame: &Framebuffer, options: &PngOptions, path: P) -> IOResult<()> {
+    let file = File::create(path)?;
+    write_png(frame, options, BufWriter::new(file))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cpu::Chip8;
+    use quirks::Quirks;
+
+    /// Draws the 0 glyph at the top left, then stops:
+    /// V0 := 0, I := glyph V0, DXY5, jump to itself.
+    const DRAW_ZERO: [u8; 8] = [0x60, 0x00, 0xF0, 0x29, 0xD0, 0x05, 0x12, 0x06];
+
+    fn run(renderer: &HeadlessRenderer, program: &[u8], frames: usize) {
+        let mut cpu = Chip8::new(Quirks::chip48(), Some(Box::new(renderer.clone())), None);
+        cpu.load_rom(program).unwrap();
+
+        for _ in 0 .. frames {
+            cpu.run_frame().unwrap();
+        }
+    }
+
+    #[test]
+    fn renders_to_png() {
+        let renderer = HeadlessRenderer::new();
+        run(&renderer, &DRAW_ZERO, 1);
+
+        let (on, off) = ([0xFF, 0x80, 0x00], [0x00, 0x00, 0x20]);
+        let options = PngOptions { scale: 2, palette: Palette::monochrome(on, off) };
+        let mut data = vec![];
+        write_png(&renderer.last().unwrap(), &options, &mut data).unwrap();
+
+        let mut reader = png::Decoder::new(&data[..]).read_info().unwrap();
+        let mut image = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut image).unwrap();
+        assert_eq!((info.width, info.height), (128, 64));
+
+        let colour = |x: usize, y: usize| {
+            let at = (y * 128 + x) * 3;
+            [image[at], image[at + 1], image[at + 2]]
+        };
+
+        // The glyph's rows are F0 90 90 90 F0, each pixel 2x2.
+        assert_eq!(colour(0, 0), on);
+        assert_eq!(colour(7, 1), on);
+        assert_eq!(colour(8, 0), off);
+        assert_eq!(colour(2, 2), off);
+        assert_eq!(colour(6, 4), on);
+        assert_eq!(colour(3, 9), on);
+        assert_eq!(colour(0, 10), off);
+    }
+
+    #[test]
+    fn keeps_only_the_last_frame() {
+        // Draw and erase the glyph forever, so every frame changes.
+        let program = [0x60, 0x00, 0xF0, 0x29, 0xD0, 0x05, 0x12, 0x04];
+
+        let renderer = HeadlessRenderer::new();
+        run(&renderer, &program, 10);
+        assert_eq!(renderer.frames().len(), 1);
+
+        let renderer = HeadlessRenderer::with_limit(3);
+        run(&renderer, &program, 10);
+        assert_eq!(renderer.frames().len(), 3);
+    }
+}
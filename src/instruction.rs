@@ -0,0 +1,313 @@
+use std::error::Error;
+use std::fmt;
+use cpu::{Opcode, Parameters};
+use quirks::Platform;
+
+/// A decoded CHIP-8 instruction, including the
+/// SUPER-CHIP and XO-CHIP extensions. X and Y are register
+/// numbers, NNN is an address and NN and N are 8 and 4 bit
+/// constants.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Instruction {
+    // 0NNN, calls RCA 1802 program at the address.
+    Sys { nnn: u16 },
+    // 00E0
+    Clear,
+    // 00EE
+    Return,
+    // 00CN
+    ScrollDown { n: u8 },
+    // 00DN
+    ScrollUp { n: u8 },
+    // 00FB
+    ScrollRight,
+    // 00FC
+    ScrollLeft,
+    // 00FD
+    Exit,
+    // 00FE
+    LowRes,
+    // 00FF
+    HighRes,
+    // 1NNN
+    Jump { nnn: u16 },
+    // 2NNN
+    Call { nnn: u16 },
+    // 3XNN
+    SkipEqImm { x: u8, nn: u8 },
+    // 4XNN
+    SkipNeImm { x: u8, nn: u8 },
+    // 5XY0
+    SkipEq { x: u8, y: u8 },
+    // 5XY2, stores VX to VY at I.
+    SaveRange { x: u8, y: u8 },
+    // 5XY3, loads VX to VY from I.
+    LoadRange { x: u8, y: u8 },
+    // 6XNN
+    LoadImm { x: u8, nn: u8 },
+    // 7XNN
+    AddImm { x: u8, nn: u8 },
+    // 8XY0
+    Load { x: u8, y: u8 },
+    // 8XY1
+    Or { x: u8, y: u8 },
+    // 8XY2
+    And { x: u8, y: u8 },
+    // 8XY3
+    Xor { x: u8, y: u8 },
+    // 8XY4
+    Add { x: u8, y: u8 },
+    // 8XY5
+    Sub { x: u8, y: u8 },
+    // 8XY6
+    ShiftRight { x: u8, y: u8 },
+    // 8XY7
+    SubReverse { x: u8, y: u8 },
+    // 8XYE
+    ShiftLeft { x: u8, y: u8 },
+    // 9XY0
+    SkipNe { x: u8, y: u8 },
+    // ANNN
+    LoadIndex { nnn: u16 },
+    // BNNN
+    JumpOffset { nnn: u16 },
+    // CXNN
+    Random { x: u8, nn: u8 },
+    // DXYN, or DXY0 for a 16x16 sprite.
+    Draw { x: u8, y: u8, n: u8 },
+    // EX9E
+    SkipKey { x: u8 },
+    // EXA1
+    SkipNotKey { x: u8 },
+    // FX07
+    LoadDelay { x: u8 },
+    // F000 NNNN, the address is in the next two bytes.
+    LongIndex,
+    // FN01, N is a mask of the planes to draw on.
+    SelectPlanes { x: u8 },
+    // F002
+    LoadPattern,
+    // FX0A
+    WaitKey { x: u8 },
+    // FX15
+    SetDelay { x: u8 },
+    // FX18
+    SetSound { x: u8 },
+    // FX1E
+    AddIndex { x: u8 },
+    // FX29
+    LoadGlyph { x: u8 },
+    // FX30
+    LoadBigGlyph { x: u8 },
+    // FX3A
+    SetPitch { x: u8 },
+    // FX33
+    StoreBcd { x: u8 },
+    // FX55
+    Store { x: u8 },
+    // FX65
+    Restore { x: u8 },
+    // FX75
+    SaveFlags { x: u8 },
+    // FX85
+    LoadFlags { x: u8 }
+}
+
+/// An opcode that does not decode to any instruction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DecodeError(pub Opcode);
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04X} is not a valid opcode", self.0)
+    }
+}
+
+impl Error for DecodeError {}
+
+impl Instruction {
+    /// Decode an opcode into an instruction.
+    pub fn decode(op: Opcode) -> Result<Instruction, DecodeError> {
+        use self::Instruction::*;
+
+        let (x, y) = (op.x(), op.y());
+
+        let instruction = match op & 0xF000 {
+            0x0000 => match op {
+                0x00E0 => Clear,
+                0x00EE => Return,
+                0x00FB => ScrollRight,
+                0x00FC => ScrollLeft,
+                0x00FD => Exit,
+                0x00FE => LowRes,
+                0x00FF => HighRes,
+                _ if op & 0xFFF0 == 0x00C0 => ScrollDown { n: op.n() },
+                _ if op & 0xFFF0 == 0x00D0 => ScrollUp { n: op.n() },
+                _ => Sys { nnn: op.nnn() }
+            },
+
+            0x1000 => Jump { nnn: op.nnn() },
+            0x2000 => Call { nnn: op.nnn() },
+            0x3000 => SkipEqImm { x, nn: op.nn() },
+            0x4000 => SkipNeImm { x, nn: op.nn() },
+            0x5000 => match op.n() {
+                0x0 => SkipEq { x, y },
+                0x2 => SaveRange { x, y },
+                0x3 => LoadRange { x, y },
+                _ => return Err(DecodeError(op))
+            },
+
+            0x6000 => LoadImm { x, nn: op.nn() },
+            0x7000 => AddImm { x, nn: op.nn() },
+
+            0x8000 => match op.n() {
+                0x0 => Load { x, y },
+                0x1 => Or { x, y },
+                0x2 => And { x, y },
+                0x3 => Xor { x, y },
+                0x4 => Add { x, y },
+                0x5 => Sub { x, y },
+                0x6 => ShiftRight { x, y },
+                0x7 => SubReverse { x, y },
+                0xE => ShiftLeft { x, y },
+                _ => return Err(DecodeError(op))
+            },
+
+            0x9000 if op.n() == 0 => SkipNe { x, y },
+            0xA000 => LoadIndex { nnn: op.nnn() },
+            0xB000 => JumpOffset { nnn: op.nnn() },
+            0xC000 => Random { x, nn: op.nn() },
+            0xD000 => Draw { x, y, n: op.n() },
+
+            0xE000 => match op.nn() {
+                0x9E => SkipKey { x },
+                0xA1 => SkipNotKey { x },
+                _ => return Err(DecodeError(op))
+            },
+
+            0xF000 => match op.nn() {
+                0x00 if x == 0 => LongIndex,
+                0x01 => SelectPlanes { x },
+                0x02 if x == 0 => LoadPattern,
+                0x07 => LoadDelay { x },
+                0x0A => WaitKey { x },
+                0x15 => SetDelay { x },
+                0x18 => SetSound { x },
+                0x1E => AddIndex { x },
+                0x29 => LoadGlyph { x },
+                0x30 => LoadBigGlyph { x },
+                0x33 => StoreBcd { x },
+                0x3A => SetPitch { x },
+                0x55 => Store { x },
+                0x65 => Restore { x },
+                0x75 => SaveFlags { x },
+                0x85 => LoadFlags { x },
+                _ => return Err(DecodeError(op))
+            },
+
+            _ => return Err(DecodeError(op))
+        };
+
+        Ok(instruction)
+    }
+
+    /// The first platform to support the instruction.
+    /// DXY0 counts as CHIP-8, where it draws nothing.
+    pub fn platform(&self) -> Platform {
+        use self::Instruction::*;
+
+        match *self {
+            SaveFlags { x } | LoadFlags { x } if x > 7 => Platform::XoChip,
+            ScrollDown { .. } | ScrollRight | ScrollLeft |
+            Exit | LowRes | HighRes | LoadBigGlyph { .. } |
+            SaveFlags { .. } | LoadFlags { .. } => Platform::SuperChip,
+            ScrollUp { .. } | SaveRange { .. } | LoadRange { .. } |
+            LongIndex | SelectPlanes { .. } | LoadPattern |
+            SetPitch { .. } => Platform::XoChip,
+            _ => Platform::Chip8
+        }
+    }
+
+    /// Length of the instruction in bytes. Only F000 NNNN
+    /// is longer than its opcode.
+    pub fn size(&self) -> usize {
+        match *self {
+            Instruction::LongIndex => 4,
+            _ => 2
+        }
+    }
+
+    /// Encode an instruction back into its opcode.
+    /// Out of range fields are masked to their width.
+    /// For F000 NNNN, this is just the F000.
+    pub fn encode(&self) -> Opcode {
+        use self::Instruction::*;
+
+        // Packs the four nibbles of an opcode.
+        fn pack(a: u16, x: u8, y: u8, n: u8) -> Opcode {
+            (a << 12) | ((x as u16 & 0xF) << 8) | ((y as u16 & 0xF) << 4) | (n as u16 & 0xF)
+        }
+
+        fn address(a: u16, nnn: u16) -> Opcode {
+            (a << 12) | (nnn & 0x0FFF)
+        }
+
+        fn constant(a: u16, x: u8, nn: u8) -> Opcode {
+            (a << 12) | ((x as u16 & 0xF) << 8) | nn as u16
+        }
+
+        match *self {
+            Sys { nnn } => address(0x0, nnn),
+            Clear => 0x00E0,
+            Return => 0x00EE,
+            ScrollDown { n } => 0x00C0 | (n as u16 & 0xF),
+            ScrollUp { n } => 0x00D0 | (n as u16 & 0xF),
+            ScrollRight => 0x00FB,
+            ScrollLeft => 0x00FC,
+            Exit => 0x00FD,
+            LowRes => 0x00FE,
+            HighRes => 0x00FF,
+            Jump { nnn } => address(0x1, nnn),
+            Call { nnn } => address(0x2, nnn),
+            SkipEqImm { x, nn } => constant(0x3, x, nn),
+            SkipNeImm { x, nn } => constant(0x4, x, nn),
+            SkipEq { x, y } => pack(0x5, x, y, 0x0),
+            SaveRange { x, y } => pack(0x5, x, y, 0x2),
+            LoadRange { x, y } => pack(0x5, x, y, 0x3),
+            LoadImm { x, nn } => constant(0x6, x, nn),
+            AddImm { x, nn } => constant(0x7, x, nn),
+            Load { x, y } => pack(0x8, x, y, 0x0),
+            Or { x, y } => pack(0x8, x, y, 0x1),
+            And { x, y } => pack(0x8, x, y, 0x2),
+            Xor { x, y } => pack(0x8, x, y, 0x3),
+            Add { x, y } => pack(0x8, x, y, 0x4),
+            Sub { x, y } => pack(0x8, x, y, 0x5),
+            ShiftRight { x, y } => pack(0x8, x, y, 0x6),
+            SubReverse { x, y } => pack(0x8, x, y, 0x7),
+            ShiftLeft { x, y } => pack(0x8, x, y, 0xE),
+            SkipNe { x, y } => pack(0x9, x, y, 0x0),
+            LoadIndex { nnn } => address(0xA, nnn),
+            JumpOffset { nnn } => address(0xB, nnn),
+            Random { x, nn } => constant(0xC, x, nn),
+            Draw { x, y, n } => pack(0xD, x, y, n),
+            SkipKey { x } => constant(0xE, x, 0x9E),
+            SkipNotKey { x } => constant(0xE, x, 0xA1),
+            LongIndex => 0xF000,
+            SelectPlanes { x } => constant(0xF, x, 0x01),
+            LoadPattern => 0xF002,
+            LoadDelay { x } => constant(0xF, x, 0x07),
+            WaitKey { x } => constant(0xF, x, 0x0A),
+            SetDelay { x } => constant(0xF, x, 0x15),
+            SetSound { x } => constant(0xF, x, 0x18),
+            AddIndex { x } => constant(0xF, x, 0x1E),
+            LoadGlyph { x } => constant(0xF, x, 0x29),
+            LoadBigGlyph { x } => constant(0xF, x, 0x30),
+            StoreBcd { x } => constant(0xF, x, 0x33),
+            SetPitch { x } => constant(0xF, x, 0x3A),
+            Store { x } => constant(0xF, x, 0x55),
+            Restore { x } => constant(0xF, x, 0x65),
+            SaveFlags { x } => constant(0xF, x, 0x75),
+            LoadFlags { x } => constant(0xF, x, 0x85)
+        }
+    }
+}
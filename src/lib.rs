@@ -0,0 +1,27 @@
+pub mod assembler;
+pub mod audio;
+pub mod cartridge;
+pub mod cosmac;
+pub mod cpu;
+pub mod disassembler;
+pub mod display;
+pub mod flags;
+pub mod headless;
+pub mod instruction;
+pub mod octo;
+pub mod quirks;
+#[cfg(feature = "sdl")]
+pub mod sdl;
+pub mod terminal;
+pub mod timing;
+
+pub use assembler::{assemble, assemble_file, Assembly, AssemblyError};
+pub use audio::{Recorder, Synth, Voice};
+pub use cartridge::Cartridge;
+pub use cpu::{Chip8, CpuError, Input, KeyEvent, Opcode, Render, Rom, Status, TimerMode, Timing};
+pub use disassembler::{disassemble, disassemble_source, Line, Syntax};
+pub use display::{Framebuffer, Palette, Region};
+pub use flags::{FileFlagStore, FlagStore};
+pub use headless::HeadlessRenderer;
+pub use instruction::Instruction;
+pub use quirks::{Platform, Quirks};
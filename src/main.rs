@@ -1,11 +1,275 @@
-mod cpu;
-mod sdl;
+extern crate chip8;
 
-use cpu::*;
-use sdl::*;
+use std::env;
+use std::path::PathBuf;
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
+use chip8::{Chip8, FileFlagStore, Input, Platform, Quirks, Recorder, Render, Status, Timing, Voice};
+use chip8::audio::SAMPLE_RATE;
+use chip8::octo;
+use chip8::cpu::FRAME_RATE;
+use chip8::terminal::Terminal;
+#[cfg(feature = "sdl")]
+use chip8::sdl::Sdl;
+
+const USAGE: &str = "\
+usage: chip8 [options] ROM
+
+ROM can also be Octo source, ending in .8o, which is compiled first,
+or an Octo cartridge GIF, whose speed, quirks and colours are used.
+
+options:
+    --scale N     size of each pixel in the window (default 10)
+    --speed N     instructions executed per frame (default 10)
+    --vip-timing  run as fast as a COSMAC VIP would, by what
+                  each instruction cost it, rather than --speed
+    --quirks P    interpreter to emulate, including its instruction
+                  set: vip, chip48, schip or xochip (default vip)
+    --terminal    play in the terminal rather than a window
+                  (always, without the sdl feature), which
+                  needs a Unix terminal with stty
+    --flags DIR   where SUPER-CHIP flags are saved between sessions
+                  (default $XDG_DATA_HOME/chip-butty/flags)
+    --wav FILE    also record the session's audio to a WAV file
+    --1802        run 0NNN as COSMAC VIP machine code, for hybrid ROMs";
+
+struct Options {
+    rom: String,
+    scale: u32,
+    speed: usize,
+    timing: Timing,
+    quirks: Quirks,
+    platform: Platform,
+    terminal: bool,
+    flags: Option<PathBuf>,
+    wav: Option<PathBuf>,
+    machine_code: bool
+}
+
+/// A frontend's input and its renderer.
+type Frontend = (Box<dyn Host>, Box<dyn Render>);
+
+/// The parts of a frontend that the host
+/// drives, beyond rendering.
+trait Host: Input {
+    fn closed(&self) -> bool;
+    fn sound(&mut self, voice: Voice);
+}
+
+#[cfg(feature = "sdl")]
+impl Host for Sdl {
+    fn closed(&self) -> bool {
+        Sdl::closed(self)
+    }
+
+    fn sound(&mut self, voice: Voice) {
+        Sdl::sound(self, voice)
+    }
+}
+
+impl Host for Terminal {
+    fn closed(&self) -> bool {
+        Terminal::closed(self)
+    }
+
+    fn sound(&mut self, voice: Voice) {
+        Terminal::beep(self, voice.playing)
+    }
+}
+
+/// Load a ROM, or compile Octo source. Compiled programs
+/// get the platform their instructions need.
+fn load(cpu: &mut Chip8, path: &str) -> Result<(), String> {
+    if path.ends_with(".8o") {
+        let program = octo::compile_file(path).map_err(|e| e.to_string())?;
+        cpu.platform = cpu.platform.max(program.platform);
+        return cpu.load_rom(&program.rom).map_err(|e| format!("couldn't load {}: {}", path, e))
+    }
+
+    cpu.load_file(path).map_err(|e| format!("couldn't load {}: {}", path, e))
+}
+
+/// Parse the command line, or explain what's wrong with it.
+fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Result<Options, String> {
+    let mut options = Options {
+        rom: String::new(),
+        scale: 10,
+        speed: 10,
+        timing: Timing::Speed,
+        quirks: Quirks::vip(),
+        platform: Platform::Chip8,
+        terminal: !cfg!(feature = "sdl"),
+        flags: FileFlagStore::default_dir(),
+        wav: None,
+        machine_code: false
+    };
+
+    let mut rom = None;
+
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("{} needs a value", arg));
+
+        match arg.as_str() {
+            "--scale" => {
+                let scale = value()?;
+                options.scale = scale.parse()
+                    .map_err(|_| format!("bad scale: {}", scale))?
+            },
+
+            "--speed" => {
+                let speed = value()?;
+                options.speed = speed.parse()
+                    .map_err(|_| format!("bad speed: {}", speed))?
+            },
+
+            "--vip-timing" => options.timing = Timing::Vip,
+
+            "--quirks" => {
+                let name = value()?;
+                options.quirks = Quirks::preset(&name)
+                    .ok_or(format!("unknown quirks preset: {}", name))?;
+                options.platform = Platform::preset(&name)
+                    .unwrap_or(Platform::Chip8)
+            },
+
+            "--terminal" => options.terminal = true,
+
+            "--flags" => options.flags = Some(PathBuf::from(value()?)),
+
+            "--wav" => options.wav = Some(PathBuf::from(value()?)),
+
+            "--1802" => options.machine_code = true,
+
+            "-h" | "--help" => return Err(String::new()),
+
+            _ if rom.is_none() && !arg.starts_with("--") => rom = Some(arg),
+
+            _ => return Err(format!("unexpected argument: {}", arg))
+        }
+    }
+
+    options.rom = rom.ok_or("no ROM given".to_string())?;
+    Ok(options)
+}
+
+/// Open the frontend chosen by the options.
+fn open(options: &Options) -> Result<Frontend, String> {
+    if options.terminal {
+        let (terminal, screen) = Terminal::new().map_err(|e| e.to_string())?;
+        return Ok((Box::new(terminal), Box::new(screen)))
+    }
+
+    #[cfg(feature = "sdl")]
+    {
+        let (sdl, screen) = Sdl::new(options.scale)?;
+        Ok((Box::new(sdl), Box::new(screen)))
+    }
+
+    #[cfg(not(feature = "sdl"))]
+    unreachable!("the terminal is always used without SDL")
+}
+
+/// Run frames until the frontend is closed,
+/// recording the audio if there's a recorder.
+fn play(cpu: &mut Chip8, host: &mut dyn Host, mut recorder: Option<&mut Recorder>) -> Result<(), String> {
+    let frame = Duration::new(0, 1_000_000_000 / FRAME_RATE);
+    let mut next = Instant::now();
+    let mut warned = false;
+
+    while !host.closed() {
+        while let Some(event) = host.poll() {
+            cpu.key_event(event)
+        }
+
+        // Leave the last frame up once the program halts.
+        match cpu.run_frame() {
+            Ok(Status::Halted) => cpu.sound = 0,
+            Ok(_) => {},
+            Err(error) => return Err(error.to_string())
+        }
+
+        if let Some(error) = cpu.renderer.as_mut().and_then(|renderer| renderer.take_error()) {
+            return Err(format!("couldn't draw: {}", error))
+        }
+
+        // The flags are still in memory, so the
+        // program runs on. Only warn the first time.
+        if let Some(error) = cpu.flag_store_error.take() {
+            if !warned {
+                eprintln!("chip8: couldn't use the flag store: {}", error);
+                warned = true
+            }
+        }
+
+        host.sound(Voice::from(&*cpu));
+
+        if let Some(ref mut recorder) = recorder {
+            recorder.record_frame(cpu)
+        }
+
+        next += frame;
+        let now = Instant::now();
+
+        if next > now {
+            thread::sleep(next - now)
+        }
+
+        else {
+            next = now
+        }
+    }
+
+    Ok(())
+}
 
 fn main() {
-    let mut cpu = Chip8::new(None);
-    cpu.load_file("/home/rose/PONG").unwrap();
-    cpu.run();
+    let options = match parse_args(env::args().skip(1)) {
+        Ok(options) => options,
+        Err(message) => {
+            if !message.is_empty() {
+                eprintln!("chip8: {}", message);
+            }
+
+            eprintln!("{}", USAGE);
+            process::exit(2)
+        }
+    };
+
+    let (mut host, screen) = match open(&options) {
+        Ok(frontend) => frontend,
+        Err(error) => {
+            eprintln!("chip8: couldn't open the frontend: {}", error);
+            process::exit(1)
+        }
+    };
+
+    let mut cpu = Chip8::new(options.quirks, Some(screen), None);
+    cpu.speed = options.speed;
+    cpu.timing = options.timing;
+    cpu.platform = options.platform;
+    cpu.machine_code = options.machine_code;
+    cpu.flag_store = options.flags.as_ref()
+        .map(|dir| Box::new(FileFlagStore::new(dir.clone())) as Box<_>);
+
+    let mut recorder = options.wav.as_ref().map(|_| Recorder::new(SAMPLE_RATE));
+
+    let result = load(&mut cpu, &options.rom)
+        .and_then(|_| play(&mut cpu, &mut *host, recorder.as_mut()));
+
+    // Whatever was recorded is saved, even after a fault.
+    let result = match (recorder, &options.wav) {
+        (Some(recorder), Some(path)) => result.and(
+            recorder.save_wav(path)
+                .map_err(|e| format!("couldn't save {}: {}", path.display(), e))),
+        _ => result
+    };
+
+    // Restore the terminal before reporting anything.
+    drop(host);
+
+    if let Err(error) = result {
+        eprintln!("chip8: {}", error);
+        process::exit(1)
+    }
 }
@@ -0,0 +1,1335 @@
+use std::collections::BTreeMap;
+use std::f64::consts;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use assembler::{Assembly, AssemblyError, Position};
+use cpu::{MEMORY_SIZE, PROGRAM_ADDRESS, XO_MEMORY_SIZE};
+use instruction::Instruction;
+use quirks::Platform;
+
+/// How many macros may be expanded while compiling,
+/// to stop macros that expand themselves forever.
+const EXPANSION_LIMIT: usize = 1 << 16;
+
+/// A word of the source, or a string in quotes.
+#[derive(Clone, Debug)]
+struct Token {
+    text: String,
+    string: bool,
+    position: Position
+}
+
+struct Macro {
+    args: Vec<String>,
+    body: Vec<Token>,
+    // How many times it's been expanded, as CALLS.
+    calls: usize
+}
+
+/// One :stringmode definition, for the characters
+/// in its alphabet.
+struct StringMode {
+    alphabet: Vec<u8>,
+    body: Vec<Token>
+}
+
+/// Where a value that wasn't defined yet goes.
+#[derive(Clone, Copy, Debug)]
+enum Patch {
+    // The low 12 bits of the instruction.
+    Address,
+    // 16 bits, this many bytes on.
+    Word(usize),
+    // :unpack's v0 := and v1 := with a nibble on top.
+    Unpack(u8)
+}
+
+struct Fixup {
+    name: String,
+    patch: Patch,
+    // The address of the instruction or data.
+    address: usize,
+    position: Position
+}
+
+/// A register compared with another register or a byte.
+struct Condition {
+    x: u8,
+    op: Token,
+    operand: Option<Result<u8, u8>>
+}
+
+/// A block of structured control flow that's still open.
+enum Block {
+    // The jump past an if ... begin.
+    If(usize),
+    // The jump past an else.
+    Else(usize),
+    // Where the loop starts, and the jumps out from whiles.
+    Loop(usize, Vec<usize>)
+}
+
+struct Compiler {
+    // The tokens still to compile, last first.
+    tokens: Vec<Token>,
+    // Where compiling got to, for errors at the end.
+    end: Position,
+    rom: Vec<u8>,
+    // Which bytes of the ROM have been written.
+    written: Vec<bool>,
+    pc: usize,
+    // Whether 0x200 is kept for a jump to main.
+    jump_to_main: bool,
+    labels: BTreeMap<String, usize>,
+    constants: BTreeMap<String, f64>,
+    aliases: BTreeMap<String, u8>,
+    macros: BTreeMap<String, Macro>,
+    string_modes: BTreeMap<String, Vec<StringMode>>,
+    expansions: usize,
+    fixups: Vec<Fixup>,
+    blocks: Vec<(Block, Position)>,
+    // The first platform that runs every instruction so far.
+    platform: Platform
+}
+
+fn error<S: Into<String>>(position: &Position, message: S) -> AssemblyError {
+    AssemblyError { position: position.clone(), message: message.into() }
+}
+
+/// Split source into tokens. Octo's tokens are separated by
+/// whitespace, comments run from # to the end of the line,
+/// and strings are in double quotes.
+fn tokenize(source: &str, file: Option<&Path>) -> Result<Vec<Token>, AssemblyError> {
+    let mut tokens = vec![];
+
+    for (number, line) in source.lines().enumerate() {
+        let chars: Vec<char> = line.chars().collect();
+        let mut i = 0;
+
+        while i < chars.len() {
+            let position = Position {
+                file: file.map(Path::to_path_buf),
+                line: number + 1,
+                column: i + 1
+            };
+
+            if chars[i].is_whitespace() {
+                i += 1;
+                continue
+            }
+
+            if chars[i] == '#' {
+                break
+            }
+
+            let mut text = String::new();
+
+            if chars[i] == '"' {
+                i += 1;
+
+                loop {
+                    match chars.get(i) {
+                        Some('"') => break,
+                        Some('\\') => {
+                            text.push(match chars.get(i + 1) {
+                                Some('n') => '\n',
+                                Some('r') => '\r',
+                                Some('t') => '\t',
+                                Some('0') => '\0',
+                                Some(&c) => c,
+                                None => return Err(error(&position, "unterminated string"))
+                            });
+
+                            i += 1
+                        },
+                        Some(&c) => text.push(c),
+                        None => return Err(error(&position, "unterminated string"))
+                    }
+
+                    i += 1
+                }
+
+                i += 1;
+                tokens.push(Token { text, string: true, position });
+                continue
+            }
+
+            while i < chars.len() && !chars[i].is_whitespace() {
+                text.push(chars[i]);
+                i += 1
+            }
+
+            tokens.push(Token { text, string: false, position })
+        }
+    }
+
+    Ok(tokens)
+}
+
+/// Read a number as Octo writes them: decimal, 0x hex or
+/// 0b binary, with an optional minus sign.
+fn number(text: &str) -> Option<f64> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text)
+    };
+
+    let value = if let Some(hex) = digits.strip_prefix("0x").or_else(|| digits.strip_prefix("0X")) {
+        i64::from_str_radix(hex, 16).ok()? as f64
+    } else if let Some(binary) = digits.strip_prefix("0b").or_else(|| digits.strip_prefix("0B")) {
+        i64::from_str_radix(binary, 2).ok()? as f64
+    } else if digits.starts_with(|c: char| c.is_ascii_digit()) {
+        digits.parse().ok()?
+    } else {
+        return None
+    };
+
+    Some(if negative { -value } else { value })
+}
+
+/// Words with a meaning of their own, which can't be names.
+fn is_keyword(text: &str) -> bool {
+    const KEYWORDS: [&str; 41] = [
+        ":", ":next", ":unpack", ":breakpoint", ":monitor", ":proto", ":alias", ":const",
+        ":macro", ":calc", ":byte", ":pointer", ":org", ":call", ":assert", ":stringmode",
+        "return", ";", "clear", "hires", "lores", "exit", "scroll-left", "scroll-right",
+        "scroll-down", "scroll-up", "audio", "plane", "bcd", "save", "load", "saveflags",
+        "loadflags", "sprite", "jump", "jump0", "delay", "buzzer", "pitch", "i", "if"
+    ];
+
+    const BLOCKS: [&str; 8] = ["then", "begin", "else", "end", "loop", "again", "while", "key"];
+
+    KEYWORDS.contains(&text) || BLOCKS.contains(&text)
+}
+
+impl Compiler {
+    fn new(mut tokens: Vec<Token>, end: Position) -> Compiler {
+        tokens.reverse();
+
+        Compiler {
+            tokens,
+            end,
+            rom: vec![],
+            written: vec![],
+            pc: PROGRAM_ADDRESS,
+            jump_to_main: true,
+            labels: BTreeMap::new(),
+            constants: BTreeMap::new(),
+            aliases: BTreeMap::new(),
+            macros: BTreeMap::new(),
+            string_modes: BTreeMap::new(),
+            expansions: 0,
+            fixups: vec![],
+            blocks: vec![],
+            platform: Platform::Chip8
+        }
+    }
+
+    fn next(&mut self) -> Result<Token, AssemblyError> {
+        match self.tokens.pop() {
+            Some(token) => {
+                self.end = token.position.clone();
+                Ok(token)
+            },
+
+            None => Err(error(&self.end, "unexpected end of the program"))
+        }
+    }
+
+    fn peek(&self) -> Option<&str> {
+        self.tokens.last().map(|token| token.text.as_str())
+    }
+
+    /// Take the next token, which must be this word.
+    fn expect(&mut self, word: &str) -> Result<Token, AssemblyError> {
+        let token = self.next()?;
+
+        if token.string || token.text != word {
+            return Err(error(&token.position, format!("expected {}, not {}", word, token.text)))
+        }
+
+        Ok(token)
+    }
+
+    /// Take a name to define, which mustn't be
+    /// a keyword, number or register.
+    fn name(&mut self) -> Result<Token, AssemblyError> {
+        let token = self.next()?;
+
+        if token.string || is_keyword(&token.text) || number(&token.text).is_some() ||
+           self.register(&token.text).is_some() {
+            return Err(error(&token.position, format!("{} can't be used as a name", token.text)))
+        }
+
+        Ok(token)
+    }
+
+    fn define_check(&self, token: &Token) -> Result<(), AssemblyError> {
+        let name = &token.text;
+
+        if self.labels.contains_key(name) || self.constants.contains_key(name) {
+            return Err(error(&token.position, format!("{} is already defined", name)))
+        }
+
+        Ok(())
+    }
+
+    /// The register a word names, by its own name or an alias.
+    fn register(&self, text: &str) -> Option<u8> {
+        if let Some(&register) = self.aliases.get(text) {
+            return Some(register)
+        }
+
+        let mut chars = text.chars();
+
+        match (chars.next(), chars.next(), chars.next()) {
+            (Some('v'), Some(digit), None) | (Some('V'), Some(digit), None) =>
+                digit.to_digit(16).map(|digit| digit as u8),
+            _ => None
+        }
+    }
+
+    fn next_register(&mut self) -> Result<u8, AssemblyError> {
+        let token = self.next()?;
+
+        match self.register(&token.text) {
+            Some(register) if !token.string => Ok(register),
+            _ => Err(error(&token.position, format!("expected a register, not {}", token.text)))
+        }
+    }
+
+    fn emit(&mut self, byte: u8, position: &Position) -> Result<(), AssemblyError> {
+        if self.pc < PROGRAM_ADDRESS || self.pc >= XO_MEMORY_SIZE {
+            return Err(error(position, format!("0x{:X} is outside the program", self.pc)))
+        }
+
+        let offset = self.pc - PROGRAM_ADDRESS;
+
+        if offset >= self.rom.len() {
+            self.rom.resize(offset + 1, 0);
+            self.written.resize(offset + 1, false)
+        }
+
+        if self.written[offset] {
+            return Err(error(position, format!("0x{:X} is already used", self.pc)))
+        }
+
+        self.rom[offset] = byte;
+        self.written[offset] = true;
+        self.pc += 1;
+        Ok(())
+    }
+
+    fn instruction(&mut self, op: u16, position: &Position) -> Result<(), AssemblyError> {
+        if let Ok(instruction) = Instruction::decode(op) {
+            self.platform = self.platform.max(instruction.platform())
+        }
+
+        self.emit((op >> 8) as u8, position)?;
+        self.emit(op as u8, position)
+    }
+
+    /// Write a value over bytes already compiled.
+    fn patch(&mut self, address: usize, patch: Patch, value: usize, position: &Position)
+        -> Result<(), AssemblyError> {
+        let offset = address - PROGRAM_ADDRESS;
+        let bits = match patch { Patch::Word(_) => 16, _ => 12 };
+
+        if value >> bits != 0 {
+            return Err(error(position, format!("0x{:X} doesn't fit in {} bits", value, bits)))
+        }
+
+        match patch {
+            Patch::Address => {
+                self.rom[offset] = (self.rom[offset] & 0xF0) | (value >> 8) as u8;
+                self.rom[offset + 1] = value as u8
+            },
+
+            Patch::Word(on) => {
+                self.rom[offset + on] = (value >> 8) as u8;
+                self.rom[offset + on + 1] = value as u8
+            },
+
+            Patch::Unpack(nibble) => {
+                self.rom[offset + 1] = (nibble << 4) | (value >> 8) as u8;
+                self.rom[offset + 3] = value as u8
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The value of a name, if it's been defined.
+    fn lookup(&self, name: &str) -> Option<f64> {
+        self.constants.get(name).cloned()
+            .or_else(|| self.labels.get(name).map(|&address| address as f64))
+    }
+
+    /// Read a value: a number, a name, or an expression in
+    /// braces. Names not defined yet are returned as Err, so
+    /// addresses can refer forward.
+    fn value(&mut self) -> Result<Result<f64, Token>, AssemblyError> {
+        let token = self.next()?;
+
+        if token.string {
+            return Err(error(&token.position, "expected a value, not a string"))
+        }
+
+        if token.text == "{" {
+            let value = self.calc()?;
+            self.expect("}")?;
+            return Ok(Ok(value))
+        }
+
+        if let Some(value) = number(&token.text).or_else(|| self.lookup(&token.text)) {
+            return Ok(Ok(value))
+        }
+
+        Ok(Err(token))
+    }
+
+    /// A value that must be defined already, in a range.
+    fn known(&mut self, min: f64, max: f64) -> Result<i64, AssemblyError> {
+        let position = self.tokens.last().map(|token| token.position.clone());
+
+        match self.value()? {
+            Ok(value) => {
+                let value = value.floor();
+
+                if value < min || value > max {
+                    let position = position.unwrap_or_else(|| self.end.clone());
+                    return Err(error(&position, format!("{} is out of range", value)))
+                }
+
+                Ok(value as i64)
+            },
+
+            Err(token) => Err(error(&token.position, format!("{} isn't defined", token.text)))
+        }
+    }
+
+    fn byte(&mut self) -> Result<u8, AssemblyError> {
+        self.known(-128.0, 255.0).map(|value| value as u8)
+    }
+
+    fn nibble(&mut self) -> Result<u8, AssemblyError> {
+        self.known(0.0, 15.0).map(|value| value as u8)
+    }
+
+    /// An address for what's compiled next, which may be a
+    /// label defined later. It's patched in at the end then,
+    /// and 0 is returned for now.
+    fn address(&mut self, patch: Patch) -> Result<usize, AssemblyError> {
+        let position = self.tokens.last().map(|token| token.position.clone());
+
+        match self.value()? {
+            Ok(value) => {
+                let max = match patch { Patch::Word(_) => 0xFFFF, _ => 0xFFF };
+
+                if value < 0.0 || value > max as f64 {
+                    let position = position.unwrap_or_else(|| self.end.clone());
+                    return Err(error(&position, format!("{} doesn't fit in an address", value)))
+                }
+
+                Ok(value as usize)
+            },
+
+            Err(token) => {
+                self.fixups.push(Fixup {
+                    name: token.text,
+                    patch,
+                    address: self.pc,
+                    position: token.position
+                });
+
+                Ok(0)
+            }
+        }
+    }
+
+    /// Evaluate a :calc expression, up to the closing brace.
+    /// Operators have no precedence, and are evaluated right
+    /// to left, so 2 * 3 + 1 is 8.
+    fn calc(&mut self) -> Result<f64, AssemblyError> {
+        let left = self.calc_term()?;
+
+        match self.peek() {
+            None | Some("}") | Some(")") => return Ok(left),
+            _ => {}
+        }
+
+        let op = self.next()?;
+        let right = self.calc()?;
+        let (a, b) = (left as i64, right as i64);
+        let truth = |condition: bool| if condition { 1.0 } else { 0.0 };
+
+        Ok(match op.text.as_str() {
+            "+" => left + right,
+            "-" => left - right,
+            "*" => left * right,
+            "/" => left / right,
+            "%" => left % right,
+            "&" => (a & b) as f64,
+            "|" => (a | b) as f64,
+            "^" => (a ^ b) as f64,
+            "<<" => a.checked_shl(b as u32).unwrap_or(0) as f64,
+            ">>" => a.checked_shr(b as u32).unwrap_or(0) as f64,
+            "pow" => left.powf(right),
+            "min" => left.min(right),
+            "max" => left.max(right),
+            "<" => truth(left < right),
+            "<=" => truth(left <= right),
+            "==" => truth(left == right),
+            "!=" => truth(left != right),
+            ">=" => truth(left >= right),
+            ">" => truth(left > right),
+            _ => return Err(error(&op.position, format!("unknown operator {}", op.text)))
+        })
+    }
+
+    fn calc_term(&mut self) -> Result<f64, AssemblyError> {
+        let token = self.next()?;
+
+        let unary = |op: &str, value: f64| -> Option<f64> {
+            Some(match op {
+                "-" => -value,
+                "~" => !(value as i64) as f64,
+                "!" => if value == 0.0 { 1.0 } else { 0.0 },
+                "sin" => value.sin(),
+                "cos" => value.cos(),
+                "tan" => value.tan(),
+                "exp" => value.exp(),
+                "log" => value.ln(),
+                "abs" => value.abs(),
+                "sqrt" => value.sqrt(),
+                "sign" => if value == 0.0 { 0.0 } else { value.signum() },
+                "ceil" => value.ceil(),
+                "floor" => value.floor(),
+                _ => return None
+            })
+        };
+
+        match token.text.as_str() {
+            "(" => {
+                let value = self.calc()?;
+                self.expect(")")?;
+                return Ok(value)
+            },
+
+            // The byte compiled at an address.
+            "@" => {
+                let address = self.calc_term()? as usize;
+                let offset = address.wrapping_sub(PROGRAM_ADDRESS);
+                return Ok(self.rom.get(offset).cloned().unwrap_or(0) as f64)
+            },
+
+            "HERE" => return Ok(self.pc as f64),
+            "PI" => return Ok(consts::PI),
+            "E" => return Ok(consts::E),
+            _ => {}
+        }
+
+        if unary(&token.text, 0.0).is_some() && !token.string {
+            let value = self.calc_term()?;
+            return Ok(unary(&token.text, value).unwrap_or(value))
+        }
+
+        if let Some(value) = number(&token.text).or_else(|| self.lookup(&token.text)) {
+            return Ok(value)
+        }
+
+        match self.register(&token.text) {
+            Some(register) => Ok(register as f64),
+            None => Err(error(&token.position, format!("{} isn't defined", token.text)))
+        }
+    }
+
+    /// Take tokens up to the closing brace, after
+    /// the opening one. Braces inside are kept.
+    fn body(&mut self) -> Result<Vec<Token>, AssemblyError> {
+        let mut body = vec![];
+        let mut depth = 0;
+
+        loop {
+            let token = self.next()?;
+
+            if !token.string {
+                match token.text.as_str() {
+                    "{" => depth += 1,
+                    "}" if depth == 0 => return Ok(body),
+                    "}" => depth -= 1,
+                    _ => {}
+                }
+            }
+
+            body.push(token)
+        }
+    }
+
+    /// Put tokens back to be compiled next,
+    /// with words replaced as they go.
+    fn expand(&mut self, body: &[Token], replace: &BTreeMap<String, String>, position: &Position)
+        -> Result<(), AssemblyError> {
+        self.expansions += 1;
+
+        if self.expansions > EXPANSION_LIMIT {
+            return Err(error(position, "macros expand too many times"))
+        }
+
+        for token in body.iter().rev() {
+            let mut token = token.clone();
+
+            if !token.string {
+                if let Some(text) = replace.get(&token.text) {
+                    token.text = text.clone()
+                }
+            }
+
+            self.tokens.push(token)
+        }
+
+        Ok(())
+    }
+
+    /// Read a condition for if or while: a register,
+    /// a comparison, and what it's compared with.
+    fn condition(&mut self) -> Result<Condition, AssemblyError> {
+        let x = self.next_register()?;
+        let op = self.next()?;
+
+        let operand = match op.text.as_str() {
+            "key" | "-key" => None,
+
+            _ => match self.peek().and_then(|text| self.register(text)) {
+                Some(y) => {
+                    self.next()?;
+                    Some(Ok(y))
+                },
+
+                None => Some(Err(self.byte()?))
+            }
+        };
+
+        Ok(Condition { x, op, operand })
+    }
+
+    /// Compile a condition as instructions that skip the next
+    /// one when it's false, or when it's true if negated.
+    fn skip_unless(&mut self, condition: Condition, negated: bool) -> Result<(), AssemblyError> {
+        let Condition { x, op, operand } = condition;
+        let position = &op.position;
+        let x = x as u16;
+        let temp = *self.aliases.get("compare-temp").unwrap_or(&0xF) as u16;
+
+        let comparison = match (op.text.as_str(), negated) {
+            ("==", true) => "!=",
+            ("!=", true) => "==",
+            ("key", true) => "-key",
+            ("-key", true) => "key",
+            ("<", true) => ">=",
+            (">", true) => "<=",
+            (">=", true) => "<",
+            ("<=", true) => ">",
+            (comparison, _) => comparison
+        };
+
+        match (comparison, operand) {
+            ("==", Some(Ok(y))) => self.instruction(0x9000 | x << 8 | (y as u16) << 4, position),
+            ("==", Some(Err(nn))) => self.instruction(0x4000 | x << 8 | nn as u16, position),
+            ("!=", Some(Ok(y))) => self.instruction(0x5000 | x << 8 | (y as u16) << 4, position),
+            ("!=", Some(Err(nn))) => self.instruction(0x3000 | x << 8 | nn as u16, position),
+            ("key", None) => self.instruction(0xE0A1 | x << 8, position),
+            ("-key", None) => self.instruction(0xE09E | x << 8, position),
+
+            // The rest compare using a temporary register, VF
+            // unless compare-temp is aliased. Subtracting sets
+            // VF when there's no borrow.
+            ("<", Some(operand)) | (">", Some(operand)) | ("<=", Some(operand)) | (">=", Some(operand)) => {
+                match operand {
+                    Ok(y) => self.instruction(0x8000 | temp << 8 | (y as u16) << 4, position)?,
+                    Err(nn) => self.instruction(0x6000 | temp << 8 | nn as u16, position)?
+                }
+
+                let greater = comparison == ">" || comparison == "<=";
+                let subtract = if greater { 0x5 } else { 0x7 };
+                let skip = if comparison == ">" || comparison == "<" { 0x3F01 } else { 0x4F01 };
+
+                self.instruction(0x8000 | temp << 8 | x << 4 | subtract, position)?;
+                self.instruction(skip, position)
+            },
+
+            _ => Err(error(position, format!("unknown condition {}", op.text)))
+        }
+    }
+
+    /// A jump to be patched when the block it leaves ends.
+    fn jump_out(&mut self, position: &Position) -> Result<usize, AssemblyError> {
+        let address = self.pc;
+        self.instruction(0x1000, position)?;
+        Ok(address)
+    }
+
+    fn statement(&mut self, token: Token) -> Result<(), AssemblyError> {
+        let position = token.position.clone();
+
+        if token.string {
+            return Err(error(&position, "a string can't be compiled here"))
+        }
+
+        if let Some(value) = number(&token.text) {
+            if !(-128.0 ..= 255.0).contains(&value) {
+                return Err(error(&position, format!("{} doesn't fit in a byte", value)))
+            }
+
+            return self.emit(value as i64 as u8, &position)
+        }
+
+        match token.text.as_str() {
+            ":" => {
+                let name = self.name()?;
+                self.define_check(&name)?;
+
+                // A program that starts with main doesn't need a jump
+                // to it. Nothing is written yet, but labels just
+                // before main move back with it.
+                if name.text == "main" && self.jump_to_main && self.pc == PROGRAM_ADDRESS + 2 && self.blocks.is_empty() {
+                    self.jump_to_main = false;
+                    self.rom.clear();
+                    self.written.clear();
+                    self.pc = PROGRAM_ADDRESS;
+
+                    for address in self.labels.values_mut() {
+                        if (PROGRAM_ADDRESS + 2 ..= PROGRAM_ADDRESS + 3).contains(address) {
+                            *address -= 2
+                        }
+                    }
+                }
+
+                self.labels.insert(name.text, self.pc);
+            },
+
+            // A label on the second byte of the next instruction,
+            // for code that changes its own operands.
+            ":next" => {
+                let name = self.name()?;
+                self.define_check(&name)?;
+                self.labels.insert(name.text, self.pc + 1);
+            },
+
+            ":unpack" => {
+                let nibble = self.nibble()? as u16;
+                let address = self.address(Patch::Unpack(nibble as u8))? as u16;
+                self.instruction(0x6000 | nibble << 4 | address >> 8, &position)?;
+                self.instruction(0x6100 | (address & 0xFF), &position)?
+            },
+
+            // Directions for Octo's debugger, which
+            // don't change what's compiled.
+            ":breakpoint" | ":proto" => { self.next()?; },
+            ":monitor" => { self.next()?; self.next()?; },
+
+            ":alias" => {
+                let name = self.name()?;
+
+                let register = match self.peek() {
+                    Some("{") => self.known(0.0, 15.0)? as u8,
+                    _ => self.next_register()?
+                };
+
+                self.aliases.insert(name.text, register);
+            },
+
+            ":const" => {
+                let name = self.name()?;
+                self.define_check(&name)?;
+                let value = self.known(f64::MIN, f64::MAX)?;
+                self.constants.insert(name.text, value as f64);
+            },
+
+            ":calc" => {
+                let name = self.name()?;
+                self.expect("{")?;
+                let value = self.calc()?;
+                self.expect("}")?;
+
+                // Calculated constants can be redefined.
+                if self.labels.contains_key(&name.text) {
+                    return Err(error(&name.position, format!("{} is already defined", name.text)))
+                }
+
+                self.constants.insert(name.text, value);
+            },
+
+            ":macro" => {
+                let name = self.name()?;
+                let mut args = vec![];
+
+                while self.peek() != Some("{") {
+                    args.push(self.next()?.text)
+                }
+
+                self.next()?;
+                let body = self.body()?;
+                self.macros.insert(name.text, Macro { args, body, calls: 0 });
+            },
+
+            ":stringmode" => {
+                let name = self.name()?;
+                let alphabet = self.next()?;
+
+                if !alphabet.string {
+                    return Err(error(&alphabet.position, "expected the characters in quotes"))
+                }
+
+                self.expect("{")?;
+                let body = self.body()?;
+
+                self.string_modes.entry(name.text).or_default()
+                    .push(StringMode { alphabet: alphabet.text.into_bytes(), body })
+            },
+
+            ":byte" => {
+                let byte = self.byte()?;
+                self.emit(byte, &position)?
+            },
+
+            ":pointer" => {
+                let address = self.address(Patch::Word(0))?;
+                self.instruction(address as u16, &position)?
+            },
+
+            ":org" => self.pc = self.known(0.0, (XO_MEMORY_SIZE - 1) as f64)? as usize,
+
+            ":call" => {
+                let address = self.address(Patch::Address)? as u16;
+                self.instruction(0x2000 | address, &position)?
+            },
+
+            ":assert" => {
+                let message = match self.tokens.last() {
+                    Some(token) if token.string => Some(self.next()?.text),
+                    _ => None
+                };
+
+                self.expect("{")?;
+                let value = self.calc()?;
+                self.expect("}")?;
+
+                if value == 0.0 {
+                    return Err(error(&position, message.unwrap_or_else(|| "assertion failed".to_string())))
+                }
+            },
+
+            "return" | ";" => self.instruction(0x00EE, &position)?,
+            "clear" => self.instruction(0x00E0, &position)?,
+            "hires" => self.instruction(0x00FF, &position)?,
+            "lores" => self.instruction(0x00FE, &position)?,
+            "exit" => self.instruction(0x00FD, &position)?,
+            "scroll-left" => self.instruction(0x00FC, &position)?,
+            "scroll-right" => self.instruction(0x00FB, &position)?,
+            "audio" => self.instruction(0xF002, &position)?,
+
+            "scroll-down" => {
+                let n = self.nibble()? as u16;
+                self.instruction(0x00C0 | n, &position)?
+            },
+
+            "scroll-up" => {
+                let n = self.nibble()? as u16;
+                self.instruction(0x00D0 | n, &position)?
+            },
+
+            "plane" => {
+                let n = self.nibble()? as u16;
+                self.instruction(0xF001 | n << 8, &position)?
+            },
+
+            "bcd" | "saveflags" | "loadflags" => {
+                let x = self.next_register()? as u16;
+
+                let op = match token.text.as_str() {
+                    "bcd" => 0xF033,
+                    "saveflags" => 0xF075,
+                    _ => 0xF085
+                };
+
+                self.instruction(op | x << 8, &position)?
+            },
+
+            // save and load take one register, or a range.
+            "save" | "load" => {
+                let x = self.next_register()? as u16;
+                if self.peek() == Some("-") {
+                    self.next()?;
+                    let y = self.next_register()? as u16;
+                    let op = if token.text == "save" { 0x5002 } else { 0x5003 };
+                    self.instruction(op | x << 8 | y << 4, &position)?
+                }
+
+                else {
+                    let op = if token.text == "save" { 0xF055 } else { 0xF065 };
+                    self.instruction(op | x << 8, &position)?
+                }
+            },
+
+            "sprite" => {
+                let x = self.next_register()? as u16;
+                let y = self.next_register()? as u16;
+                let n = self.nibble()? as u16;
+                self.instruction(0xD000 | x << 8 | y << 4 | n, &position)?
+            },
+
+            "jump" | "jump0" => {
+                let address = self.address(Patch::Address)? as u16;
+                let op = if token.text == "jump" { 0x1000 } else { 0xB000 };
+                self.instruction(op | address, &position)?
+            },
+
+            "delay" | "buzzer" | "pitch" => {
+                self.expect(":=")?;
+                let x = self.next_register()? as u16;
+
+                let op = match token.text.as_str() {
+                    "delay" => 0xF015,
+                    "buzzer" => 0xF018,
+                    _ => 0xF03A
+                };
+
+                self.instruction(op | x << 8, &position)?
+            },
+
+            "i" => {
+                let op = self.next()?;
+
+                match op.text.as_str() {
+                    "+=" => {
+                        let x = self.next_register()? as u16;
+                        self.instruction(0xF01E | x << 8, &position)?
+                    },
+
+                    ":=" => match self.peek() {
+                        Some("long") => {
+                            self.next()?;
+                            let address = self.address(Patch::Word(2))? as u16;
+                            self.instruction(0xF000, &position)?;
+                            self.instruction(address, &position)?
+                        },
+
+                        Some("hex") | Some("bighex") => {
+                            let op = if self.next()?.text == "hex" { 0xF029 } else { 0xF030 };
+                            let x = self.next_register()? as u16;
+                            self.instruction(op | x << 8, &position)?
+                        },
+
+                        _ => {
+                            let address = self.address(Patch::Address)? as u16;
+                            self.instruction(0xA000 | address, &position)?
+                        }
+                    },
+
+                    _ => return Err(error(&op.position, format!("expected := or += after i, not {}", op.text)))
+                }
+            },
+
+            "if" => {
+                let condition = self.condition()?;
+                let follows = self.next()?;
+
+                match follows.text.as_str() {
+                    "then" => self.skip_unless(condition, false)?,
+
+                    "begin" => {
+                        self.skip_unless(condition, true)?;
+                        let jump = self.jump_out(&position)?;
+                        self.blocks.push((Block::If(jump), position))
+                    },
+
+                    _ => return Err(error(&follows.position, format!("expected then or begin, not {}", follows.text)))
+                }
+            },
+
+            "else" => match self.blocks.pop() {
+                Some((Block::If(jump), _)) => {
+                    let out = self.jump_out(&position)?;
+                    let pc = self.pc;
+                    self.patch(jump, Patch::Address, pc, &position)?;
+                    self.blocks.push((Block::Else(out), position))
+                },
+
+                _ => return Err(error(&position, "else without if ... begin"))
+            },
+
+            "end" => match self.blocks.pop() {
+                Some((Block::If(jump), _)) | Some((Block::Else(jump), _)) => {
+                    let pc = self.pc;
+                    self.patch(jump, Patch::Address, pc, &position)?
+                },
+
+                _ => return Err(error(&position, "end without if ... begin"))
+            },
+
+            "loop" => {
+                let pc = self.pc;
+                self.blocks.push((Block::Loop(pc, vec![]), position))
+            },
+
+            "while" => {
+                let condition = self.condition()?;
+                self.skip_unless(condition, true)?;
+                let jump = self.jump_out(&position)?;
+
+                let found = self.blocks.iter_mut().rev().find_map(|block| match *block {
+                    (Block::Loop(_, ref mut whiles), _) => Some(whiles),
+                    _ => None
+                });
+
+                match found {
+                    Some(whiles) => whiles.push(jump),
+                    None => return Err(error(&position, "while outside a loop"))
+                }
+            },
+
+            "again" => match self.blocks.pop() {
+                Some((Block::Loop(start, whiles), _)) => {
+                    self.instruction(0x1000 | start as u16, &position)?;
+
+                    for jump in whiles {
+                        let pc = self.pc;
+                        self.patch(jump, Patch::Address, pc, &position)?
+                    }
+                },
+
+                _ => return Err(error(&position, "again without loop"))
+            },
+
+            text => {
+                if let Some(x) = self.register(text) {
+                    return self.assignment(x as u16, &position)
+                }
+
+                if self.macros.contains_key(text) {
+                    return self.expand_macro(token)
+                }
+
+                if self.string_modes.contains_key(text) {
+                    return self.expand_string(token)
+                }
+
+                if is_keyword(text) {
+                    return Err(error(&position, format!("unexpected {}", text)))
+                }
+
+                // Any other name is a subroutine to call.
+                self.tokens.push(token);
+                let address = self.address(Patch::Address)? as u16;
+                self.instruction(0x2000 | address, &position)?
+            }
+        }
+
+        Ok(())
+    }
+
+    /// An operation on a register: vx := ..., vx += ... and so on.
+    fn assignment(&mut self, x: u16, position: &Position) -> Result<(), AssemblyError> {
+        let op = self.next()?;
+        let y = self.peek().and_then(|text| self.register(text)).map(|y| y as u16);
+
+        if let Some(y) = y {
+            let n = match op.text.as_str() {
+                ":=" => 0x0,
+                "|=" => 0x1,
+                "&=" => 0x2,
+                "^=" => 0x3,
+                "+=" => 0x4,
+                "-=" => 0x5,
+                ">>=" => 0x6,
+                "=-" => 0x7,
+                "<<=" => 0xE,
+                _ => return Err(error(&op.position, format!("unknown operator {}", op.text)))
+            };
+
+            self.next()?;
+            return self.instruction(0x8000 | x << 8 | y << 4 | n, position)
+        }
+
+        match (op.text.as_str(), self.peek()) {
+            (":=", Some("random")) => {
+                self.next()?;
+                let nn = self.byte()? as u16;
+                self.instruction(0xC000 | x << 8 | nn, position)
+            },
+
+            (":=", Some("key")) => {
+                self.next()?;
+                self.instruction(0xF00A | x << 8, position)
+            },
+
+            (":=", Some("delay")) => {
+                self.next()?;
+                self.instruction(0xF007 | x << 8, position)
+            },
+
+            (":=", _) => {
+                let nn = self.byte()? as u16;
+                self.instruction(0x6000 | x << 8 | nn, position)
+            },
+
+            ("+=", _) => {
+                let nn = self.byte()? as u16;
+                self.instruction(0x7000 | x << 8 | nn, position)
+            },
+
+            ("-=", _) => {
+                let nn = self.byte()?.wrapping_neg() as u16;
+                self.instruction(0x7000 | x << 8 | nn, position)
+            },
+
+            _ => Err(error(&op.position, format!("{} needs a register", op.text)))
+        }
+    }
+
+    fn expand_macro(&mut self, token: Token) -> Result<(), AssemblyError> {
+        let (names, calls) = {
+            let definition = &self.macros[&token.text];
+            (definition.args.clone(), definition.calls)
+        };
+
+        let mut replace = BTreeMap::new();
+        replace.insert("CALLS".to_string(), calls.to_string());
+
+        for name in names {
+            let arg = self.next()?;
+            replace.insert(name, arg.text);
+        }
+
+        let definition = self.macros.get_mut(&token.text).expect("macro is defined");
+        definition.calls += 1;
+        let body = definition.body.clone();
+
+        self.expand(&body, &replace, &token.position)
+    }
+
+    /// Compile a string with a :stringmode, which expands the
+    /// body for each character, in reverse as they're pushed.
+    fn expand_string(&mut self, token: Token) -> Result<(), AssemblyError> {
+        let text = self.next()?;
+
+        if !text.string {
+            return Err(error(&text.position, format!("{} needs a string", token.text)))
+        }
+
+        let mut expansions = vec![];
+
+        for (index, &byte) in text.text.as_bytes().iter().enumerate() {
+            let found = self.string_modes[&token.text].iter().find_map(|mode| {
+                mode.alphabet.iter().position(|&c| c == byte).map(|value| (value, mode.body.clone()))
+            });
+
+            let (value, body) = match found {
+                Some(found) => found,
+                None => return Err(error(&text.position,
+                    format!("{} has no mode for {:?}", token.text, byte as char)))
+            };
+
+            let mut replace = BTreeMap::new();
+            replace.insert("CHAR".to_string(), byte.to_string());
+            replace.insert("INDEX".to_string(), index.to_string());
+            replace.insert("VALUE".to_string(), value.to_string());
+            expansions.push((body, replace))
+        }
+
+        for (body, replace) in expansions.iter().rev() {
+            self.expand(body, replace, &token.position)?
+        }
+
+        Ok(())
+    }
+
+    fn compile(mut self) -> Result<Assembly, AssemblyError> {
+        // Room for a jump to main, unless main comes first.
+        let start = self.end.clone();
+        self.instruction(0x0000, &start)?;
+
+        while !self.tokens.is_empty() {
+            let token = self.next()?;
+            self.statement(token)?
+        }
+
+        if let Some((block, position)) = self.blocks.pop() {
+            let message = match block {
+                Block::Loop(..) => "loop without again",
+                _ => "if ... begin without end"
+            };
+
+            return Err(error(&position, message))
+        }
+
+        if self.jump_to_main {
+            match self.labels.get("main") {
+                Some(&main) => self.patch(PROGRAM_ADDRESS, Patch::Address, main, &start)?,
+                None => return Err(error(&start, "the program has no main label"))
+            }
+
+            self.rom[0] |= 0x10
+        }
+
+        for fixup in std::mem::take(&mut self.fixups) {
+            match self.labels.get(&fixup.name).cloned() {
+                Some(address) => self.patch(fixup.address, fixup.patch, address, &fixup.position)?,
+                None => return Err(error(&fixup.position, format!("{} isn't defined", fixup.name)))
+            }
+        }
+
+        let mut symbols: BTreeMap<String, i64> = self.labels.iter()
+            .map(|(name, &address)| (name.clone(), address as i64))
+            .collect();
+
+        for (name, &value) in &self.constants {
+            symbols.insert(name.clone(), value.floor() as i64);
+        }
+
+        if self.rom.len() > MEMORY_SIZE - PROGRAM_ADDRESS {
+            self.platform = Platform::XoChip
+        }
+
+        Ok(Assembly { rom: self.rom, symbols, platform: self.platform })
+    }
+}
+
+/// Compile Octo source given as a string. Like Octo, the
+/// program must have a main label, and starts with a jump
+/// to it unless it's at the start.
+pub fn compile(source: &str) -> Result<Assembly, AssemblyError> {
+    let tokens = tokenize(source, None)?;
+    let start = Position { file: None, line: 1, column: 1 };
+
+    Compiler::new(tokens, start).compile()
+}
+
+/// Compile an Octo source file.
+pub fn compile_file<P: AsRef<Path>>(path: P) -> Result<Assembly, AssemblyError> {
+    let path = path.as_ref();
+    let mut source = String::new();
+    let start = Position { file: Some(path.to_path_buf()), line: 0, column: 0 };
+
+    File::open(path).and_then(|mut file| file.read_to_string(&mut source))
+        .map_err(|e| error(&start, e.to_string()))?;
+
+    let tokens = tokenize(&source, Some(path))?;
+    Compiler::new(tokens, Position { line: 1, column: 1, ..start }).compile()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use disassembler::{disassemble_source, Syntax};
+
+    #[test]
+    fn compiles_loops_and_conditions() {
+        let assembly = compile("\
+:alias x v1
+:const SPEED 2
+: main
+  x := 0
+  loop
+    x += SPEED
+    if x == 10 then x := 0
+    i := digit
+    sprite x x 5
+  again
+: digit
+  0xF0 0x90
+").unwrap();
+
+        assert_eq!(assembly.rom, [
+            0x61, 0x00, 0x71, 0x02, 0x41, 0x0A, 0x61, 0x00,
+            0xA2, 0x0E, 0xD1, 0x15, 0x12, 0x02, 0xF0, 0x90
+        ]);
+
+        assert_eq!(assembly.symbols["digit"], 0x20E);
+        assert_eq!(assembly.symbols["SPEED"], 2);
+    }
+
+    #[test]
+    fn jumps_to_main_after_other_code() {
+        let assembly = compile("\
+: sub return
+: main
+  v0 := 1
+  if v0 == v1 begin
+    sub
+  else
+    v2 += 3
+  end
+  jump main
+").unwrap();
+
+        assert_eq!(assembly.rom, [
+            0x12, 0x04, 0x00, 0xEE, 0x60, 0x01, 0x50, 0x10, 0x12, 0x0E,
+            0x22, 0x02, 0x12, 0x10, 0x72, 0x03, 0x12, 0x04
+        ]);
+    }
+
+    #[test]
+    fn moves_labels_with_main() {
+        let assembly = compile(": start :next op : main v0 := 1 jump start").unwrap();
+        assert_eq!(assembly.rom, [0x60, 0x01, 0x12, 0x00]);
+        assert_eq!(assembly.symbols["start"], 0x200);
+        assert_eq!(assembly.symbols["op"], 0x201);
+    }
+
+    #[test]
+    fn reports_the_platform_needed() {
+        let platform = |source| compile(source).unwrap().platform;
+        assert_eq!(platform(": main clear"), Platform::Chip8);
+        assert_eq!(platform(": main hires"), Platform::SuperChip);
+        assert_eq!(platform(": main saveflags v9"), Platform::XoChip);
+        assert_eq!(platform(": main i := long main"), Platform::XoChip);
+    }
+
+    #[test]
+    fn reports_line_and_column() {
+        let cases = [
+            (": main\n  v0 := 300\n", "2:9: 300 is out of range"),
+            (": main\n  jump nowhere\n", "2:8: nowhere isn't defined"),
+            ("  v0 := 1\n", "1:1: the program has no main label"),
+            (": main\n  loop\n", "2:3: loop without again"),
+            (": main\n  frob\n", "2:3: frob isn't defined")
+        ];
+
+        for &(source, message) in &cases {
+            assert_eq!(compile(source).unwrap_err().to_string(), message);
+        }
+    }
+
+    #[test]
+    fn disassembly_compiles_to_the_same_bytes() {
+        let mut roms = vec![
+            vec![0x00, 0xE0, 0xA2, 0x0A, 0x22, 0x08, 0x12, 0x06, 0x00, 0xEE, 0x3C, 0x42, 0x81],
+            vec![0x30, 0x00, 0x12, 0x0A, 0x60, 0x01, 0x12, 0x0A, 0x00, 0xE0, 0x12, 0x0A],
+            vec![0xF0, 0x00, 0x02, 0x08, 0xF0, 0x02, 0x00, 0xFF, 0x12, 0x08, 0xFF, 0x00]
+        ];
+
+        // And made up ROMs, the same every run.
+        let mut state: u32 = 0x2545_F491;
+
+        for _ in 0 .. 300 {
+            let mut next = || {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state
+            };
+
+            let length = 1 + next() as usize % 64;
+            roms.push((0 .. length).map(|_| next() as u8).collect())
+        }
+
+        for rom in roms {
+            let source = disassemble_source(&rom, Syntax::Octo);
+            let assembly = compile(&source).unwrap_or_else(|e| panic!("{}\n{}", e, source));
+            assert_eq!(assembly.rom, rom, "\n{}", source);
+        }
+    }
+}
@@ -0,0 +1,109 @@
+/// The instruction set extensions an interpreter supports.
+/// Each platform includes everything before it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Platform {
+    /// The original instruction set.
+    Chip8,
+    /// SUPER-CHIP 1.1, adding high resolution,
+    /// scrolling, 16x16 sprites and the large font.
+    SuperChip,
+    /// XO-CHIP, adding 64K of memory, bitplanes
+    /// and an audio pattern buffer.
+    XoChip
+}
+
+impl Platform {
+    /// The platform that goes with a Quirks preset.
+    pub fn preset(name: &str) -> Option<Platform> {
+        match name {
+            "vip" | "chip48" => Some(Platform::Chip8),
+            "schip" => Some(Platform::SuperChip),
+            "xochip" => Some(Platform::XoChip),
+            _ => None
+        }
+    }
+}
+
+/// Behaviour that differs between CHIP-8 interpreters.
+/// ROMs written against one interpreter may rely on
+/// its choices, so pick the preset a ROM was made for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Quirks {
+    /// 8XY6 and 8XYE shift VY into VX,
+    /// rather than shifting VX in place.
+    pub shift_vy: bool,
+    /// FX55 and FX65 leave I pointing just
+    /// past the last register stored or loaded.
+    pub increment_index: bool,
+    /// BNNN is read as BXNN, jumping to
+    /// XNN plus VX rather than NNN plus V0.
+    pub jump_vx: bool,
+    /// 8XY1, 8XY2 and 8XY3 reset VF to zero.
+    pub logic_reset_vf: bool,
+    /// Sprites wrap around the edges of
+    /// the screen rather than being clipped.
+    pub wrap_sprites: bool,
+    /// DXYN waits for the display interrupt,
+    /// so at most one sprite is drawn each frame.
+    pub display_wait: bool,
+    /// 8XY4 to 8XYE set VF before the result, so the
+    /// result is kept when VX is VF, as Octo can.
+    pub flag_before_result: bool
+}
+
+impl Quirks {
+    /// The original COSMAC VIP interpreter.
+    pub fn vip() -> Quirks {
+        Quirks {
+            shift_vy: true,
+            increment_index: true,
+            jump_vx: false,
+            logic_reset_vf: true,
+            wrap_sprites: false,
+            display_wait: true,
+            flag_before_result: false
+        }
+    }
+
+    /// CHIP-48 on the HP-48 calculators.
+    pub fn chip48() -> Quirks {
+        Quirks {
+            shift_vy: false,
+            increment_index: false,
+            jump_vx: true,
+            logic_reset_vf: false,
+            wrap_sprites: false,
+            display_wait: false,
+            flag_before_result: false
+        }
+    }
+
+    /// SUPER-CHIP 1.1, which keeps the CHIP-48 behaviour.
+    pub fn schip() -> Quirks {
+        Quirks::chip48()
+    }
+
+    /// XO-CHIP as implemented by Octo.
+    pub fn xochip() -> Quirks {
+        Quirks {
+            shift_vy: true,
+            increment_index: true,
+            jump_vx: false,
+            logic_reset_vf: false,
+            wrap_sprites: true,
+            display_wait: false,
+            flag_before_result: false
+        }
+    }
+
+    /// Look up a preset by its name.
+    pub fn preset(name: &str) -> Option<Quirks> {
+        match name {
+            "vip" => Some(Quirks::vip()),
+            "chip48" => Some(Quirks::chip48()),
+            "schip" => Some(Quirks::schip()),
+            "xochip" => Some(Quirks::xochip()),
+            _ => None
+        }
+    }
+}
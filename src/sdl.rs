@@ -1,9 +1,199 @@
 extern crate sdl2;
-use self::sdl2::*;
-use cpu::Render;
 
-impl Render for Sdl {
-    fn clear(&self, screen: &mut [[bool; 64]; 32]) {
-        *screen = [[false; 64]; 32];
+use self::sdl2::EventPump;
+use self::sdl2::audio::{AudioCallback, AudioDevice, AudioSpecDesired};
+use self::sdl2::event::Event;
+use self::sdl2::keyboard::Scancode;
+use self::sdl2::pixels::Color;
+use self::sdl2::rect::Rect;
+use self::sdl2::render::Canvas;
+use self::sdl2::video::Window;
+use cpu::{Input, KeyEvent, Render};
+use display::{Framebuffer, Palette, HEIGHT, WIDTH};
+use audio::{Synth, Voice, SAMPLE_RATE};
+
+/// An SDL2 keyboard and beeper, for a window opened
+/// alongside it. For a headless session, set SDL_VIDEODRIVER
+/// and SDL_AUDIODRIVER to "dummy" before calling new().
+pub struct Sdl {
+    events: EventPump,
+    // No beeper if the audio device couldn't be opened.
+    beeper: Option<AudioDevice<Synth>>,
+    // Set once the window has been closed.
+    closed: bool
+}
+
+/// The SDL2 window, which renders the screen.
+pub struct Screen {
+    canvas: Canvas<Window>,
+    // Size of each low resolution pixel in the window.
+    scale: u32,
+    // Colour of each pixel value.
+    palette: Palette,
+    // The last error drawing a frame.
+    error: Option<String>
+}
+
+impl AudioCallback for Synth {
+    type Channel = f32;
+
+    fn callback(&mut self, out: &mut [f32]) {
+        self.fill(out)
+    }
+}
+
+/// Maps the left hand side of a keyboard
+/// onto the hex keypad, by key position:
+///
+///     1 2 3 4        1 2 3 C
+///     Q W E R   ->   4 5 6 D
+///     A S D F        7 8 9 E
+///     Z X C V        A 0 B F
+fn keypad(scancode: Scancode) -> Option<u8> {
+    let key = match scancode {
+        Scancode::Num1 => 0x1, Scancode::Num2 => 0x2, Scancode::Num3 => 0x3, Scancode::Num4 => 0xC,
+        Scancode::Q => 0x4, Scancode::W => 0x5, Scancode::E => 0x6, Scancode::R => 0xD,
+        Scancode::A => 0x7, Scancode::S => 0x8, Scancode::D => 0x9, Scancode::F => 0xE,
+        Scancode::Z => 0xA, Scancode::X => 0x0, Scancode::C => 0xB, Scancode::V => 0xF,
+        _ => return None
+    };
+
+    Some(key)
+}
+
+impl Sdl {
+    /// Open a window big enough for the screen at the given
+    /// scale. The Screen should be handed to the interpreter
+    /// as its renderer, while the host polls the Sdl for input.
+    pub fn new(scale: u32) -> Result<(Sdl, Screen), String> {
+        let context = sdl2::init()?;
+        let video = context.video()?;
+
+        let (width, height) = (WIDTH as u32 * scale, HEIGHT as u32 * scale);
+        let window = video.window("chip-butty", width, height)
+            .position_centered()
+            .build()
+            .map_err(|e| e.to_string())?;
+
+        let canvas = window.into_canvas()
+            .build()
+            .map_err(|e| e.to_string())?;
+
+        let spec = AudioSpecDesired {
+            freq: Some(SAMPLE_RATE as i32),
+            channels: Some(1),
+            samples: None
+        };
+
+        // Carry on silently without audio.
+        let beeper = context.audio().and_then(|audio| {
+            audio.open_playback(None, &spec, |spec| Synth::new(spec.freq as u32))
+        }).ok();
+
+        let sdl = Sdl {
+            events: context.event_pump()?,
+            beeper,
+            closed: false
+        };
+
+        Ok((sdl, Screen { canvas, scale, palette: Palette::default(), error: None }))
+    }
+
+    /// Whether the window has been closed.
+    pub fn closed(&self) -> bool {
+        self.closed
+    }
+
+    /// Play the interpreter's audio, which should
+    /// be updated every frame.
+    pub fn sound(&mut self, voice: Voice) {
+        if let Some(ref mut beeper) = self.beeper {
+            beeper.lock().set_voice(voice);
+            if voice.playing { beeper.resume() } else { beeper.pause() }
+        }
+    }
+}
+
+impl Input for Sdl {
+    fn poll(&mut self) -> Option<KeyEvent> {
+        // Skip over anything that isn't a keypad key.
+        while let Some(event) = self.events.poll_event() {
+            match event {
+                Event::Quit { .. } |
+                Event::KeyDown { scancode: Some(Scancode::Escape), .. } => self.closed = true,
+
+                Event::KeyDown { scancode: Some(scancode), repeat: false, .. } => {
+                    if let Some(key) = keypad(scancode) {
+                        return Some(KeyEvent::Pressed(key))
+                    }
+                },
+
+                Event::KeyUp { scancode: Some(scancode), .. } => {
+                    if let Some(key) = keypad(scancode) {
+                        return Some(KeyEvent::Released(key))
+                    }
+                },
+
+                _ => {}
+            }
+        }
+
+        None
+    }
+}
+
+impl Screen {
+    /// Change the colours pixels are drawn in.
+    pub fn set_palette(&mut self, palette: Palette) {
+        self.palette = palette
+    }
+
+    fn draw(&mut self, frame: &Framebuffer) -> Result<(), String> {
+        let [r, g, b] = self.palette.colour(0);
+        self.canvas.set_draw_color(Color::RGB(r, g, b));
+        self.canvas.clear();
+
+        // The window stays the same size, so high resolution
+        // pixels are drawn smaller. Each pixel's edges are
+        // worked out from the window size, so an odd scale
+        // still fills the window without gaps.
+        let (width, height) = (WIDTH as u32 * self.scale, HEIGHT as u32 * self.scale);
+        let column = |x: usize| x as u32 * width / frame.width() as u32;
+        let line = |y: usize| y as u32 * height / frame.height() as u32;
+
+        for y in 0 .. frame.height() {
+            for (x, &pixel) in frame.row(y).iter().enumerate() {
+                if pixel != 0 {
+                    let [r, g, b] = self.palette.colour(pixel);
+                    self.canvas.set_draw_color(Color::RGB(r, g, b));
+
+                    let rect = Rect::new(column(x) as i32, line(y) as i32,
+                                         column(x + 1) - column(x),
+                                         line(y + 1) - line(y));
+                    self.canvas.fill_rect(rect)?;
+                }
+            }
+        }
+
+        self.canvas.present();
+        Ok(())
+    }
+}
+
+impl Render for Screen {
+    // The whole frame is redrawn, as SDL doesn't
+    // keep the contents of the window between frames.
+    fn present(&mut self, frame: &Framebuffer) {
+        if let Err(error) = self.draw(frame) {
+            self.error = Some(error)
+        }
+    }
+
+    fn set_palette(&mut self, palette: Palette) {
+        Screen::set_palette(self, palette)
+    }
+
+    fn take_error(&mut self) -> Option<String> {
+        self.error.take()
     }
 }
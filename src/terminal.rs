@@ -0,0 +1,282 @@
+use std::collections::VecDeque;
+use std::io::{self, Error as IOError, Read, Result as IOResult, Stdout, Write};
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::{Duration, Instant};
+use cpu::{Input, KeyEvent, Render};
+use display::{Framebuffer, Region};
+
+/// Terminals only report key presses, so a key is
+/// released once it hasn't been seen for this long.
+/// Holding a key relies on the terminal's key repeat,
+/// which waits longer before the first repeat.
+const RELEASE_AFTER: Duration = Duration::from_millis(500);
+
+/// How long a key can go unseen once it's repeating.
+const RELEASE_REPEATING_AFTER: Duration = Duration::from_millis(150);
+
+/// How long to wait for the rest of an escape sequence.
+/// Escape on its own quits, but arrow and function keys
+/// and Alt send sequences starting with it.
+const ESCAPE_WAIT: Duration = Duration::from_millis(25);
+
+/// Keyboard input from stdin, in raw mode.
+/// The terminal is restored when this is dropped.
+pub struct Terminal {
+    // Bytes read from stdin by a background thread.
+    bytes: Receiver<u8>,
+    // When each held key was last seen,
+    // and whether it has repeated yet.
+    held: [Option<(Instant, bool)>; 16],
+    // Events waiting to be polled.
+    events: VecDeque<KeyEvent>,
+    // Settings to restore with stty.
+    saved: String,
+    // Whether the sound timer was set last frame.
+    beeping: bool,
+    // Set once escape or ^C has been pressed.
+    closed: bool
+}
+
+/// The terminal's screen, drawn with Unicode half blocks
+/// so that each character cell holds two pixels.
+/// Pixels on any XO-CHIP plane are drawn alike.
+pub struct Screen {
+    out: Stdout,
+    // Character rows that need redrawing.
+    dirty: Vec<bool>,
+    // The last error drawing a frame.
+    error: Option<String>
+}
+
+/// Maps the left hand side of a keyboard
+/// onto the hex keypad, as the SDL frontend does.
+fn keypad(byte: u8) -> Option<u8> {
+    let key = match byte.to_ascii_lowercase() {
+        b'1' => 0x1, b'2' => 0x2, b'3' => 0x3, b'4' => 0xC,
+        b'q' => 0x4, b'w' => 0x5, b'e' => 0x6, b'r' => 0xD,
+        b'a' => 0x7, b's' => 0x8, b'd' => 0x9, b'f' => 0xE,
+        b'z' => 0xA, b'x' => 0x0, b'c' => 0xB, b'v' => 0xF,
+        _ => return None
+    };
+
+    Some(key)
+}
+
+/// Run stty against the terminal on stdin. The terminal
+/// frontend needs it on the path to change modes.
+fn stty(args: &[&str]) -> IOResult<String> {
+    let output = Command::new("stty")
+        .args(args)
+        .stdin(Stdio::inherit())
+        .output()?;
+
+    if output.status.success() {
+        Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    }
+
+    else {
+        Err(IOError::other("stdin is not a terminal"))
+    }
+}
+
+impl Terminal {
+    /// Put the terminal into raw mode and start reading
+    /// keys. The Screen should be handed to the interpreter
+    /// as its renderer, while the host polls the Terminal.
+    pub fn new() -> IOResult<(Terminal, Screen)> {
+        let saved = stty(&["-g"])?;
+        stty(&["raw", "-echo"])?;
+
+        let (sender, bytes) = mpsc::channel();
+
+        // There's no portable way to poll stdin,
+        // so block on it in the background instead.
+        thread::spawn(move || {
+            let mut stdin = io::stdin();
+            let mut buffer = [0; 64];
+
+            while let Ok(count) = stdin.read(&mut buffer) {
+                if count == 0 { break }
+
+                for &byte in &buffer[.. count] {
+                    if sender.send(byte).is_err() { return }
+                }
+            }
+        });
+
+        let terminal = Terminal {
+            bytes,
+            held: [None; 16],
+            events: VecDeque::new(),
+            saved,
+            beeping: false,
+            closed: false
+        };
+
+        let mut out = io::stdout();
+
+        // Clear the screen and hide the cursor.
+        write!(out, "\x1b[2J\x1b[?25l")?;
+        out.flush()?;
+
+        Ok((terminal, Screen { out, dirty: vec![], error: None }))
+    }
+
+    /// Whether escape or ^C has been pressed.
+    pub fn closed(&self) -> bool {
+        self.closed
+    }
+
+    /// Ring the terminal bell when the beeper starts.
+    /// The bell can't be held, so stopping does nothing.
+    pub fn beep(&mut self, on: bool) {
+        if on && !self.beeping {
+            let mut out = io::stdout();
+            let _ = out.write_all(b"\x07").and_then(|_| out.flush());
+        }
+
+        self.beeping = on
+    }
+
+    /// Skip the rest of an escape sequence, from an arrow or
+    /// function key or Alt. Escape alone closes the terminal.
+    fn escape(&mut self) {
+        let next = match self.bytes.recv_timeout(ESCAPE_WAIT) {
+            Ok(byte) => byte,
+            Err(_) => {
+                self.closed = true;
+                return
+            }
+        };
+
+        match next {
+            // CSI: parameters up to a final byte
+            // from @ to ~, as in ESC [ 1 ; 5 A.
+            b'[' => while let Ok(byte) = self.bytes.recv_timeout(ESCAPE_WAIT) {
+                if (0x40 ..= 0x7E).contains(&byte) { break }
+            },
+
+            // SS3: a single byte, as in ESC O P.
+            b'O' => {
+                let _ = self.bytes.recv_timeout(ESCAPE_WAIT);
+            },
+
+            // Alt and another key.
+            _ => {}
+        }
+    }
+}
+
+impl Input for Terminal {
+    fn poll(&mut self) -> Option<KeyEvent> {
+        let now = Instant::now();
+
+        while let Ok(byte) = self.bytes.try_recv() {
+            if byte == 0x03 {
+                self.closed = true
+            }
+
+            else if byte == 0x1B {
+                self.escape()
+            }
+
+            else if let Some(key) = keypad(byte) {
+                let seen = &mut self.held[key as usize];
+
+                if seen.is_none() {
+                    self.events.push_back(KeyEvent::Pressed(key))
+                }
+
+                *seen = Some((now, seen.is_some()))
+            }
+        }
+
+        for key in 0 .. 16 {
+            if let Some((seen, repeating)) = self.held[key] {
+                let timeout = if repeating { RELEASE_REPEATING_AFTER } else { RELEASE_AFTER };
+
+                if now - seen >= timeout {
+                    self.held[key] = None;
+                    self.events.push_back(KeyEvent::Released(key as u8))
+                }
+            }
+        }
+
+        self.events.pop_front()
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        // Show the cursor again and leave
+        // it below the last frame.
+        print!("\x1b[0m\x1b[?25h\r\n");
+        let _ = io::stdout().flush();
+        let _ = stty(&[&self.saved]);
+    }
+}
+
+impl Screen {
+    fn draw(&mut self, frame: &Framebuffer) -> IOResult<()> {
+        let rows = frame.height().div_ceil(2);
+
+        // Redraw everything if the resolution changed.
+        if self.dirty.len() != rows {
+            self.dirty = vec![true; rows];
+            write!(self.out, "\x1b[2J")?;
+        }
+
+        let mut buffer = String::new();
+
+        for row in 0 .. rows {
+            if !self.dirty[row] { continue }
+            self.dirty[row] = false;
+
+            buffer.push_str(&format!("\x1b[{};1H", row + 1));
+
+            for x in 0 .. frame.width() {
+                let top = frame.get(x, row * 2);
+                let bottom = frame.get(x, row * 2 + 1);
+
+                buffer.push(match (top, bottom) {
+                    (true, true) => '█',
+                    (true, false) => '▀',
+                    (false, true) => '▄',
+                    (false, false) => ' '
+                });
+            }
+        }
+
+        self.out.write_all(buffer.as_bytes())?;
+        self.out.flush()
+    }
+}
+
+impl Render for Screen {
+    fn present(&mut self, frame: &Framebuffer) {
+        // The error can't be shown while the terminal
+        // is in use, so keep it for the host.
+        if let Err(error) = self.draw(frame) {
+            self.error = Some(error.to_string())
+        }
+    }
+
+    fn dirty(&mut self, region: Region) {
+        if region.height == 0 { return }
+
+        let first = region.y / 2;
+        let last = (region.y + region.height - 1) / 2;
+
+        for row in first ..= last {
+            if let Some(dirty) = self.dirty.get_mut(row) {
+                *dirty = true
+            }
+        }
+    }
+
+    fn take_error(&mut self) -> Option<String> {
+        self.error.take()
+    }
+}
@@ -0,0 +1,64 @@
+use instruction::Instruction;
+
+/// 1802 machine cycles in each 60Hz frame of the COSMAC VIP.
+/// Its 1.76MHz clock takes 8 clock pulses per machine cycle.
+pub const VIP_FRAME_CYCLES: u64 = 3668;
+
+/// Machine cycles of each frame taken by the display:
+/// 128 lines of 8 bytes of DMA, plus the interrupt routine
+/// that sets it up and counts down the timers.
+pub const VIP_DISPLAY_CYCLES: u64 = 128 * 8 + 28;
+
+/// Machine cycles left for the interpreter each frame.
+pub const VIP_PROGRAM_CYCLES: u64 = VIP_FRAME_CYCLES - VIP_DISPLAY_CYCLES;
+
+/// Machine cycles the VIP interpreter spends fetching
+/// and dispatching every instruction.
+pub const VIP_FETCH_CYCLES: u64 = 40;
+
+/// Machine cycles the VIP interpreter takes to execute an
+/// instruction, fetch included. VX is only used by DXYN,
+/// which is slower when X isn't a multiple of 8, as each
+/// row of the sprite is shifted across two bytes. Skips cost
+/// more when taken. Extensions the VIP doesn't have are
+/// charged as a cheap instruction, and 0NNN is charged for
+/// its call alone, as the machine code counts its own cycles.
+pub fn vip_cycles(instruction: Instruction, vx: u8, skipped: bool) -> u64 {
+    use instruction::Instruction::*;
+
+    let skip = if skipped { 4 } else { 0 };
+
+    let execute = match instruction {
+        Sys { .. } => 8,
+        Clear => 24,
+        Return => 10,
+        Jump { .. } => 12,
+        Call { .. } => 26,
+        SkipEqImm { .. } | SkipNeImm { .. } => 10 + skip,
+        SkipEq { .. } | SkipNe { .. } => 14 + skip,
+        LoadImm { .. } => 6,
+        AddImm { .. } => 10,
+
+        Load { .. } | Or { .. } | And { .. } | Xor { .. } | Add { .. } |
+        Sub { .. } | ShiftRight { .. } | SubReverse { .. } | ShiftLeft { .. } => 44,
+
+        LoadIndex { .. } => 12,
+        JumpOffset { .. } => 22,
+        Random { .. } => 36,
+
+        Draw { n, .. } => {
+            let row = if vx & 0x7 == 0 { 34 } else { 46 };
+            26 + n as u64 * row
+        },
+
+        SkipKey { .. } | SkipNotKey { .. } => 14 + skip,
+        LoadDelay { .. } | WaitKey { .. } | SetDelay { .. } | SetSound { .. } => 10,
+        AddIndex { .. } => 16,
+        LoadGlyph { .. } => 20,
+        StoreBcd { .. } => 164,
+        Store { x } | Restore { x } => 14 + 14 * (x as u64 + 1),
+        _ => 10
+    };
+
+    VIP_FETCH_CYCLES + execute
+}